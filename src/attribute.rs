@@ -1,7 +1,11 @@
-use crate::configuration::Path;
+use crate::configuration::{Path, ValueFormat};
 use crate::filter::http_context::Filter;
+use cel_interpreter::objects::ValueType;
 use chrono::{DateTime, FixedOffset};
-use proxy_wasm::traits::Context;
+use proxy_wasm::types::Bytes;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
 
 pub trait Attribute {
     fn parse(raw_attribute: Vec<u8>) -> Result<Self, String>
@@ -104,12 +108,76 @@ impl Attribute for DateTime<FixedOffset> {
     }
 }
 
-#[allow(dead_code)]
+// Envoy serializes maps as the number of pairs, the sizes of each key and value,
+// then each key and value followed by a NUL byte
+impl Attribute for HashMap<String, String> {
+    fn parse(raw_attribute: Vec<u8>) -> Result<Self, String> {
+        let mut strings = parse_serialized_strings(&raw_attribute, 2)?.into_iter();
+        let mut map = HashMap::new();
+        while let (Some(key), Some(value)) = (strings.next(), strings.next()) {
+            map.insert(key, value);
+        }
+        Ok(map)
+    }
+}
+
+// Lists are serialized alike, with items instead of pairs
+impl Attribute for Vec<String> {
+    fn parse(raw_attribute: Vec<u8>) -> Result<Self, String> {
+        parse_serialized_strings(&raw_attribute, 1)
+    }
+}
+
+fn parse_serialized_strings(raw_attribute: &[u8], per_entry: usize) -> Result<Vec<String>, String> {
+    if raw_attribute.is_empty() {
+        return Ok(Vec::new());
+    }
+    let read_size = |offset: usize| {
+        raw_attribute
+            .get(offset..offset + 4)
+            .map(|bytes| {
+                u32::from_le_bytes(bytes.try_into().expect("This has to be 4 bytes long!")) as usize
+            })
+            .ok_or_else(|| "parse: serialized value truncated".to_string())
+    };
+    let entries = read_size(0)?;
+    if entries > raw_attribute.len() / (4 * per_entry) {
+        return Err(format!("parse: invalid number of entries: {entries}"));
+    }
+    let sizes = (0..entries * per_entry)
+        .map(|i| read_size(4 + 4 * i))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut offset = 4 + 4 * sizes.len();
+    let mut strings = Vec::with_capacity(sizes.len());
+    for size in sizes {
+        let bytes = raw_attribute
+            .get(offset..offset + size)
+            .ok_or_else(|| "parse: serialized value truncated".to_string())?;
+        strings.push(String::parse(bytes.to_vec())?);
+        // skipping the NUL byte
+        offset += size + 1;
+    }
+    Ok(strings)
+}
+
+#[cfg(test)]
+pub fn serialize_strings(strings: &[&str], entries: usize) -> Vec<u8> {
+    let mut bytes = (entries as u32).to_le_bytes().to_vec();
+    for string in strings {
+        bytes.extend_from_slice(&(string.len() as u32).to_le_bytes());
+    }
+    for string in strings {
+        bytes.extend_from_slice(string.as_bytes());
+        bytes.push(0);
+    }
+    bytes
+}
+
 pub fn get_attribute<T>(f: &Filter, attr: &str) -> Result<T, String>
 where
     T: Attribute,
 {
-    match f.get_property(Path::from(attr).tokens()) {
+    match f.resolve_property(Path::from(attr).tokens()) {
         None => Err(format!(
             "#{} get_attribute: not found: {}",
             f.context_id, attr
@@ -117,3 +185,245 @@ where
         Some(attribute_bytes) => T::parse(attribute_bytes),
     }
 }
+
+// Parses a raw attribute into a CEL value of the given type
+pub fn cel_value(
+    cel_type: &ValueType,
+    raw_attribute: Vec<u8>,
+) -> Result<cel_interpreter::Value, String> {
+    use cel_interpreter::Value as CelValue;
+    Ok(match cel_type {
+        ValueType::String => CelValue::String(Arc::new(Attribute::parse(raw_attribute)?)),
+        ValueType::Int => CelValue::Int(Attribute::parse(raw_attribute)?),
+        ValueType::UInt => CelValue::UInt(Attribute::parse(raw_attribute)?),
+        ValueType::Float => CelValue::Float(Attribute::parse(raw_attribute)?),
+        ValueType::Bytes => CelValue::Bytes(Arc::new(Attribute::parse(raw_attribute)?)),
+        ValueType::Bool => CelValue::Bool(Attribute::parse(raw_attribute)?),
+        ValueType::Timestamp => CelValue::Timestamp(Attribute::parse(raw_attribute)?),
+        ValueType::Map => CelValue::from(HashMap::<String, String>::parse(raw_attribute)?),
+        ValueType::List => CelValue::List(Arc::new(
+            Vec::<String>::parse(raw_attribute)?
+                .into_iter()
+                .map(CelValue::from)
+                .collect(),
+        )),
+        _ => {
+            return Err(format!(
+                "cel_value: values of type `{cel_type}` are not supported"
+            ))
+        }
+    })
+}
+
+// Renders a raw attribute of the given type as a descriptor value
+pub fn render_attribute(
+    cel_type: Option<ValueType>,
+    format: Option<ValueFormat>,
+    raw_attribute: Vec<u8>,
+) -> Result<String, String> {
+    match (cel_type, format) {
+        (None | Some(ValueType::String) | Some(ValueType::Bytes), Some(ValueFormat::Base64)) => {
+            Ok(base64_encode(&raw_attribute))
+        }
+        (None | Some(ValueType::String) | Some(ValueType::Bytes), Some(ValueFormat::Hex)) => {
+            Ok(raw_attribute.iter().map(|b| format!("{b:02x}")).collect())
+        }
+        (None | Some(ValueType::String), _) => String::parse(raw_attribute),
+        // bytes are rendered as text, unless they are not valid UTF-8
+        (Some(ValueType::Bytes), _) => match String::from_utf8(raw_attribute) {
+            Ok(text) => Ok(text),
+            Err(err) => Ok(base64_encode(err.as_bytes())),
+        },
+        (Some(ValueType::Int), _) => i64::parse(raw_attribute).map(|v| v.to_string()),
+        (Some(ValueType::UInt), _) => u64::parse(raw_attribute).map(|v| v.to_string()),
+        (Some(ValueType::Float), _) => f64::parse(raw_attribute).map(|v| v.to_string()),
+        (Some(ValueType::Bool), _) => bool::parse(raw_attribute).map(|v| v.to_string()),
+        (Some(ValueType::Timestamp), Some(ValueFormat::Unix)) => {
+            DateTime::<FixedOffset>::parse(raw_attribute).map(|v| v.timestamp().to_string())
+        }
+        (Some(ValueType::Timestamp), _) => {
+            DateTime::<FixedOffset>::parse(raw_attribute).map(|v| v.to_rfc3339())
+        }
+        (Some(cel_type), _) => Err(format!(
+            "render_attribute: values of type `{cel_type}` are not supported"
+        )),
+    }
+}
+
+// Standard base64, with padding
+fn base64_encode(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | (u32::from(*b) << (16 - 8 * i)));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+// Attributes produced by the filter itself (e.g. the outcome of the auth action) are kept as JSON.
+pub fn lookup_json_attribute<'a>(root: &'a Map<String, Value>, path: &[&str]) -> Option<&'a Value> {
+    let (first, rest) = path.split_first()?;
+    rest.iter()
+        .try_fold(root.get(*first)?, |value, token| match value {
+            Value::Object(object) => object.get(*token),
+            Value::Array(array) => array.get(token.parse::<usize>().ok()?),
+            _ => None,
+        })
+}
+
+// Strings are exposed as is, any other value as its JSON representation
+pub fn json_attribute_bytes(value: &Value) -> Bytes {
+    match value {
+        Value::String(string) => string.as_bytes().to_vec(),
+        _ => value.to_string().into_bytes(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::attribute::{
+        base64_encode, cel_value, json_attribute_bytes, lookup_json_attribute, render_attribute,
+        serialize_strings, Attribute,
+    };
+    use crate::configuration::ValueFormat;
+    use cel_interpreter::objects::ValueType;
+    use cel_interpreter::Value as CelValue;
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    #[test]
+    fn parse_serialized_maps() {
+        let raw = serialize_strings(&["app", "toystore", "version", "v1"], 2);
+        let map = HashMap::<String, String>::parse(raw).unwrap();
+        assert_eq!(
+            map,
+            HashMap::from([
+                ("app".to_string(), "toystore".to_string()),
+                ("version".to_string(), "v1".to_string()),
+            ])
+        );
+        assert!(HashMap::<String, String>::parse(Vec::new())
+            .unwrap()
+            .is_empty());
+
+        let mut truncated = serialize_strings(&["app", "toystore"], 1);
+        truncated.truncate(truncated.len() - 4);
+        assert!(HashMap::<String, String>::parse(truncated).is_err());
+        assert!(HashMap::<String, String>::parse(vec![255, 255, 255, 255]).is_err());
+    }
+
+    #[test]
+    fn parse_serialized_lists() {
+        let raw = serialize_strings(&["a", "", "c"], 3);
+        assert_eq!(
+            Vec::<String>::parse(raw).unwrap(),
+            vec!["a".to_string(), "".to_string(), "c".to_string()]
+        );
+        assert!(Vec::<String>::parse(vec![1, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn cel_values_of_maps_and_lists() {
+        let raw = serialize_strings(&["app", "toystore"], 1);
+        assert_eq!(
+            cel_value(&ValueType::Map, raw),
+            Ok(CelValue::from(HashMap::from([("app", "toystore")])))
+        );
+        let raw = serialize_strings(&["a", "b"], 2);
+        assert_eq!(
+            cel_value(&ValueType::List, raw),
+            Ok(CelValue::List(
+                vec![CelValue::from("a"), CelValue::from("b")].into()
+            ))
+        );
+        assert!(cel_value(&ValueType::Function, Vec::new()).is_err());
+    }
+
+    #[test]
+    fn lookup_json_attribute_nested() {
+        let root = json!({
+            "auth": {
+                "identity": { "sub": "alice", "groups": ["admin", "dev"], "age": 30 },
+            }
+        });
+        let root = root.as_object().unwrap();
+
+        let value = lookup_json_attribute(root, &["auth", "identity", "sub"]);
+        assert_eq!(value.map(json_attribute_bytes), Some(b"alice".to_vec()));
+
+        let value = lookup_json_attribute(root, &["auth", "identity", "groups", "1"]);
+        assert_eq!(value.map(json_attribute_bytes), Some(b"dev".to_vec()));
+
+        let value = lookup_json_attribute(root, &["auth", "identity", "age"]);
+        assert_eq!(value.map(json_attribute_bytes), Some(b"30".to_vec()));
+
+        let value = lookup_json_attribute(root, &["auth", "identity", "groups"]);
+        assert_eq!(
+            value.map(json_attribute_bytes),
+            Some(br#"["admin","dev"]"#.to_vec())
+        );
+
+        assert!(lookup_json_attribute(root, &["auth", "metadata"]).is_none());
+        assert!(lookup_json_attribute(root, &["auth", "identity", "sub", "x"]).is_none());
+        assert!(lookup_json_attribute(root, &["request", "path"]).is_none());
+    }
+
+    #[test]
+    fn render_typed_attributes() {
+        assert_eq!(
+            render_attribute(None, None, b"GET".to_vec()),
+            Ok("GET".to_string())
+        );
+        assert_eq!(
+            render_attribute(Some(ValueType::Int), None, 8080i64.to_le_bytes().to_vec()),
+            Ok("8080".to_string())
+        );
+        assert_eq!(
+            render_attribute(Some(ValueType::Bool), None, vec![1]),
+            Ok("true".to_string())
+        );
+        let nanos = 1_700_000_000_123_000_000i64.to_le_bytes().to_vec();
+        assert_eq!(
+            render_attribute(Some(ValueType::Timestamp), None, nanos.clone()),
+            Ok("2023-11-14T22:13:20.123+00:00".to_string())
+        );
+        assert_eq!(
+            render_attribute(Some(ValueType::Timestamp), Some(ValueFormat::Unix), nanos),
+            Ok("1700000000".to_string())
+        );
+        assert_eq!(
+            render_attribute(Some(ValueType::Bytes), None, vec![0xff, 0x00]),
+            Ok("/wA=".to_string())
+        );
+        assert_eq!(
+            render_attribute(
+                Some(ValueType::Bytes),
+                Some(ValueFormat::Hex),
+                vec![0xff, 0x00]
+            ),
+            Ok("ff00".to_string())
+        );
+        // malformed ints drop the descriptor
+        assert!(render_attribute(Some(ValueType::Int), None, vec![1, 2]).is_err());
+        assert!(render_attribute(Some(ValueType::Map), None, vec![]).is_err());
+    }
+
+    #[test]
+    fn base64() {
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foo"), "Zm9v");
+        assert_eq!(base64_encode(b"foobar"), "Zm9vYmFy");
+    }
+}
@@ -0,0 +1,127 @@
+// Host and optional port of a request authority, or of a policy hostname.
+//
+// Hosts are lowercased and stripped of their trailing dot, IPv6 literals of their brackets,
+// e.g. `[2001:DB8::1]:8080` is the host `2001:db8::1` on port 8080.
+// Internationalized domain names are converted to ASCII, e.g. `Bücher.example` is
+// `xn--bcher-kva.example`.
+//
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Authority {
+    pub host: String,
+    pub port: Option<u16>,
+}
+
+impl Authority {
+    pub fn parse(authority: &str) -> Result<Self, String> {
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(rest) => {
+                let (host, port) = rest
+                    .split_once(']')
+                    .ok_or_else(|| format!("invalid authority `{authority}`: missing `]`"))?;
+                match port {
+                    "" => (host, None),
+                    _ => (
+                        host,
+                        Some(port.strip_prefix(':').ok_or_else(|| {
+                            format!("invalid authority `{authority}`: expected a port after `]`")
+                        })?),
+                    ),
+                }
+            }
+            None => match authority.rsplit_once(':') {
+                // IPv6 literals without brackets can't have a port
+                Some((host, _)) if host.contains(':') => (authority, None),
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        let port = match port {
+            None | Some("") => None,
+            Some(port) => Some(
+                port.parse::<u16>()
+                    .map_err(|_| format!("invalid authority `{authority}`: invalid port"))?,
+            ),
+        };
+        let host = host.strip_suffix('.').unwrap_or(host);
+        if host.is_empty() {
+            return Err(format!("invalid authority `{authority}`: empty host"));
+        }
+        let host = if host.contains(':') {
+            host.to_ascii_lowercase()
+        } else {
+            idna::domain_to_ascii(host)
+                .ok()
+                // besides the letters, digits and `-` of hostnames, `_` is common and the others
+                // are wildcards
+                .filter(|host| {
+                    host.chars().all(|ch| {
+                        ch.is_ascii_alphanumeric()
+                            || matches!(ch, '-' | '.' | '_' | '*' | '?' | '+')
+                    })
+                })
+                .ok_or_else(|| format!("invalid authority `{authority}`: invalid hostname"))?
+        };
+        Ok(Authority { host, port })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn authority(host: &str, port: Option<u16>) -> Authority {
+        Authority {
+            host: host.to_string(),
+            port,
+        }
+    }
+
+    #[test]
+    fn parse_authorities() {
+        let fixtures = [
+            ("example.com", authority("example.com", None)),
+            ("Example.COM:8443", authority("example.com", Some(8443))),
+            ("example.com.", authority("example.com", None)),
+            ("example.com.:80", authority("example.com", Some(80))),
+            ("example.com:", authority("example.com", None)),
+            ("10.0.0.1:8080", authority("10.0.0.1", Some(8080))),
+            ("[2001:DB8::1]:8080", authority("2001:db8::1", Some(8080))),
+            ("[2001:db8::1]", authority("2001:db8::1", None)),
+            ("2001:db8::1", authority("2001:db8::1", None)),
+            ("*.example.com:443", authority("*.example.com", Some(443))),
+            ("*", authority("*", None)),
+            ("Bücher.example", authority("xn--bcher-kva.example", None)),
+            (
+                "xn--bcher-kva.example",
+                authority("xn--bcher-kva.example", None),
+            ),
+            (
+                "*.bücher.example:443",
+                authority("*.xn--bcher-kva.example", Some(443)),
+            ),
+            (
+                "api-*.bücher.example",
+                authority("api-*.xn--bcher-kva.example", None),
+            ),
+        ];
+        for (input, expected) in fixtures {
+            assert_eq!(Authority::parse(input), Ok(expected), "{input}");
+        }
+    }
+
+    #[test]
+    fn parse_invalid_authorities() {
+        for input in [
+            "",
+            ":8080",
+            "example.com:http",
+            "example.com:65536",
+            "[2001:db8::1",
+            "[2001:db8::1]8080",
+            "exa mple.com",
+            "xn--a.example",
+        ] {
+            assert!(Authority::parse(input).is_err(), "{input}");
+        }
+    }
+}
@@ -0,0 +1,401 @@
+use crate::attribute::cel_value;
+use crate::configuration::type_of;
+use crate::filter::http_context::Filter;
+use crate::heap_size::HeapSize;
+use cel_interpreter::extractors::This;
+use cel_interpreter::objects::{Map, ValueType};
+use cel_interpreter::{Context, Value};
+use cel_parser::{Expression, Member};
+use serde::Deserialize;
+use std::cell::OnceCell;
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+
+// Variables of the expressions, backed by the request attributes
+const ROOTS: [&str; 9] = [
+    "request",
+    "source",
+    "destination",
+    "connection",
+    "upstream",
+    "metadata",
+    "filter_state",
+    "xds",
+    "auth",
+];
+
+// A CEL expression over the request attributes, only the attributes it refers to are fetched
+#[derive(Clone)]
+pub struct Program {
+    expression: Expression,
+    attributes: Vec<Vec<String>>,
+}
+
+impl Debug for Program {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Program({:?})", self.expression)
+    }
+}
+
+impl Program {
+    pub fn compile(source: &str) -> Result<Self, String> {
+        let expression = cel_parser::parse(source)
+            .map_err(|e| format!("invalid CEL expression `{source}`: {e}"))?;
+        let mut attributes = Vec::new();
+        collect_attributes(&expression, &mut attributes);
+        attributes.sort();
+        attributes.dedup();
+        Ok(Program {
+            expression,
+            attributes,
+        })
+    }
+
+    pub fn eval(&self, filter: &Filter) -> Result<Value, String> {
+        let mut variables: HashMap<String, Node> = HashMap::new();
+        for path in &self.attributes {
+            let (root, rest) = path.split_first().expect("attribute paths are not empty");
+            let value = match root.as_str() {
+                "auth" => filter.attributes.get("auth").map(json_value),
+                _ if rest.is_empty() => None,
+                _ if path.join(".") == "request.headers" => {
+                    Some(Value::from(filter.request_headers()))
+                }
+                _ => {
+                    let tokens: Vec<&str> = path.iter().map(String::as_str).collect();
+                    match filter.resolve_property(tokens) {
+                        None => None,
+                        Some(raw_attribute) => Some(cel_value(
+                            &type_of(&path.join(".")).unwrap_or(ValueType::String),
+                            raw_attribute,
+                        )?),
+                    }
+                }
+            };
+            let node = variables.entry(root.clone()).or_insert_with(Node::map);
+            if let Some(value) = value {
+                node.insert(rest, value);
+            }
+        }
+
+        let mut ctx = Context::default();
+        ctx.add_function("lowerAscii", lower_ascii);
+        ctx.add_function("upperAscii", upper_ascii);
+        for (name, node) in variables {
+            ctx.add_variable_from_value(name, node.into_value());
+        }
+        Value::resolve(&self.expression, &ctx)
+            .map_err(|err| format!("Error evaluating {:?}: {}", self.expression, err))
+    }
+}
+
+// Extensions of the CEL standard library
+fn lower_ascii(This(this): This<Arc<String>>) -> String {
+    this.to_ascii_lowercase()
+}
+
+fn upper_ascii(This(this): This<Arc<String>>) -> String {
+    this.to_ascii_uppercase()
+}
+
+// Value of a descriptor entry computed by an expression
+pub fn descriptor_value(value: Value) -> Result<String, String> {
+    match value {
+        Value::String(string) => Ok(string.to_string()),
+        Value::Int(int) => Ok(int.to_string()),
+        Value::UInt(uint) => Ok(uint.to_string()),
+        Value::Float(float) => Ok(float.to_string()),
+        Value::Bool(boolean) => Ok(boolean.to_string()),
+        other => Err(format!(
+            "expression evaluated to a value that is not a string, number or bool: {other:?}"
+        )),
+    }
+}
+
+// The attributes the expression refers to, as paths from one of the roots.
+// `auth` and map attributes (e.g. `request.headers`) are fetched as a whole.
+fn collect_attributes(expression: &Expression, attributes: &mut Vec<Vec<String>>) {
+    if let Some(mut path) = attribute_path(expression) {
+        if ROOTS.contains(&path[0].as_str()) {
+            if path[0] == "auth" {
+                path.truncate(1);
+            } else if let Some(len) = (2..path.len())
+                .find(|len| matches!(type_of(&path[..*len].join(".")), Some(ValueType::Map)))
+            {
+                path.truncate(len);
+            }
+            attributes.push(path);
+            return;
+        }
+    }
+    match expression {
+        Expression::Arithmetic(left, _, right)
+        | Expression::Relation(left, _, right)
+        | Expression::Or(left, right)
+        | Expression::And(left, right) => {
+            collect_attributes(left, attributes);
+            collect_attributes(right, attributes);
+        }
+        Expression::Ternary(condition, left, right) => {
+            collect_attributes(condition, attributes);
+            collect_attributes(left, attributes);
+            collect_attributes(right, attributes);
+        }
+        Expression::Unary(_, operand) => collect_attributes(operand, attributes),
+        Expression::Member(target, member) => {
+            collect_attributes(target, attributes);
+            match member.as_ref() {
+                Member::Attribute(_) => {}
+                Member::Index(index) => collect_attributes(index, attributes),
+                Member::Fields(fields) => {
+                    for (_, field) in fields {
+                        collect_attributes(field, attributes);
+                    }
+                }
+            }
+        }
+        Expression::FunctionCall(_, target, args) => {
+            if let Some(target) = target {
+                collect_attributes(target, attributes);
+            }
+            for arg in args {
+                collect_attributes(arg, attributes);
+            }
+        }
+        Expression::List(items) => {
+            for item in items {
+                collect_attributes(item, attributes);
+            }
+        }
+        Expression::Map(entries) => {
+            for (key, value) in entries {
+                collect_attributes(key, attributes);
+                collect_attributes(value, attributes);
+            }
+        }
+        Expression::Atom(_) | Expression::Ident(_) => {}
+    }
+}
+
+// e.g. `request.method` is `["request", "method"]`
+fn attribute_path(expression: &Expression) -> Option<Vec<String>> {
+    match expression {
+        Expression::Ident(ident) => Some(vec![ident.to_string()]),
+        Expression::Member(target, member) => match member.as_ref() {
+            Member::Attribute(name) => {
+                let mut path = attribute_path(target)?;
+                path.push(name.to_string());
+                Some(path)
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn json_value(value: &serde_json::Value) -> Value {
+    match value {
+        serde_json::Value::Null => Value::Null,
+        serde_json::Value::Bool(boolean) => Value::Bool(*boolean),
+        serde_json::Value::Number(number) => match (number.as_i64(), number.as_u64()) {
+            (Some(int), _) => Value::Int(int),
+            (None, Some(uint)) => Value::UInt(uint),
+            _ => Value::Float(number.as_f64().unwrap_or_default()),
+        },
+        serde_json::Value::String(string) => Value::String(Arc::new(string.clone())),
+        serde_json::Value::Array(items) => {
+            Value::List(Arc::new(items.iter().map(json_value).collect()))
+        }
+        serde_json::Value::Object(fields) => Value::Map(Map::from(
+            fields
+                .iter()
+                .map(|(key, field)| (key.clone(), json_value(field)))
+                .collect::<HashMap<String, Value>>(),
+        )),
+    }
+}
+
+// Attributes of a variable, nested by path
+enum Node {
+    Value(Value),
+    Map(HashMap<String, Node>),
+}
+
+impl Node {
+    fn map() -> Self {
+        Node::Map(HashMap::new())
+    }
+
+    fn insert(&mut self, path: &[String], value: Value) {
+        match (self, path.split_first()) {
+            (node, None) => *node = Node::Value(value),
+            (Node::Map(children), Some((first, rest))) => children
+                .entry(first.clone())
+                .or_insert_with(Node::map)
+                .insert(rest, value),
+            // an attribute and one of its fields are both referenced, keep the attribute
+            (Node::Value(_), Some(_)) => {}
+        }
+    }
+
+    fn into_value(self) -> Value {
+        match self {
+            Node::Value(value) => value,
+            Node::Map(children) => Value::from(
+                children
+                    .into_iter()
+                    .map(|(name, child)| (name, child.into_value()))
+                    .collect::<HashMap<String, Value>>(),
+            ),
+        }
+    }
+}
+
+// A CEL expression a request must satisfy, e.g. `request.method in ['POST', 'PUT']`
+#[derive(Deserialize, Debug, Clone)]
+#[serde(from = "String")]
+pub struct Predicate {
+    pub source: String,
+    program: OnceCell<Program>,
+}
+
+impl From<String> for Predicate {
+    fn from(source: String) -> Self {
+        Predicate {
+            source,
+            program: OnceCell::new(),
+        }
+    }
+}
+
+impl Predicate {
+    pub fn compile(&self) -> Result<(), String> {
+        self.program
+            .set(Program::compile(&self.source)?)
+            .map_err(|_| format!("predicate `{}` compiled twice", self.source))
+    }
+
+    pub fn eval(&self, filter: &Filter) -> Result<bool, String> {
+        let program = self
+            .program
+            .get()
+            .expect("Predicate wasn't previously compiled!");
+        match program.eval(filter)? {
+            Value::Bool(result) => Ok(result),
+            other => Err(format!(
+                "predicate `{}` evaluated to a non boolean value: {other:?}",
+                self.source
+            )),
+        }
+    }
+}
+
+// The program is counted as its source
+impl HeapSize for Predicate {
+    fn heap_size(&self) -> usize {
+        2 * self.source.heap_size()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attributes_of(source: &str) -> Vec<Vec<String>> {
+        Program::compile(source).unwrap().attributes
+    }
+
+    fn path(tokens: &[&str]) -> Vec<String> {
+        tokens.iter().map(|token| token.to_string()).collect()
+    }
+
+    #[test]
+    fn program_collects_referenced_attributes() {
+        assert_eq!(
+            attributes_of(
+                "request.method in ['POST', 'PUT'] && request.headers['x-tier'] != 'gold'"
+            ),
+            vec![path(&["request", "headers"]), path(&["request", "method"])]
+        );
+        assert_eq!(
+            attributes_of("auth.identity.groups.exists(g, g == 'admin') || source.port > 1024"),
+            vec![path(&["auth"]), path(&["source", "port"])]
+        );
+        assert_eq!(
+            attributes_of("size(request.headers.authorization) > 0 ? xds.route_name : 'none'"),
+            vec![path(&["request", "headers"]), path(&["xds", "route_name"])]
+        );
+        assert!(attributes_of("[1, 2].map(x, x * 2) == [2, 4]").is_empty());
+    }
+
+    #[test]
+    fn program_rejects_invalid_expressions() {
+        assert!(Program::compile("request.method ==").is_err());
+        assert!(Program::compile("request.method in [").is_err());
+    }
+
+    #[test]
+    fn program_fetches_map_attributes_whole() {
+        assert_eq!(
+            attributes_of("source.labels['app'] == 'toystore' && 'v1' in destination.labels"),
+            vec![
+                path(&["destination", "labels"]),
+                path(&["source", "labels"])
+            ]
+        );
+    }
+
+    #[test]
+    fn json_values_are_typed() {
+        let value = json_value(&serde_json::json!({
+            "sub": "alice",
+            "age": 30,
+            "groups": ["admin"],
+            "verified": true,
+        }));
+        let mut ctx = Context::default();
+        ctx.add_variable_from_value("auth", value);
+        let expression = cel_parser::parse(
+            "auth.sub == 'alice' && auth.age > 18 && 'admin' in auth.groups && auth.verified",
+        )
+        .unwrap();
+        assert_eq!(Value::resolve(&expression, &ctx), Ok(Value::Bool(true)));
+    }
+
+    #[test]
+    fn string_functions() {
+        let mut ctx = Context::default();
+        ctx.add_function("lowerAscii", lower_ascii);
+        ctx.add_function("upperAscii", upper_ascii);
+        let expression =
+            cel_parser::parse("'user:' + 'ACME'.lowerAscii() + ':' + 'gold'.upperAscii()").unwrap();
+        assert_eq!(
+            Value::resolve(&expression, &ctx),
+            Ok(Value::from("user:acme:GOLD"))
+        );
+    }
+
+    #[test]
+    fn descriptor_values() {
+        assert_eq!(descriptor_value(Value::from("a")), Ok("a".to_string()));
+        assert_eq!(descriptor_value(Value::Int(-3)), Ok("-3".to_string()));
+        assert_eq!(descriptor_value(Value::Bool(true)), Ok("true".to_string()));
+        assert!(descriptor_value(Value::Null).is_err());
+    }
+
+    #[test]
+    fn nodes_nest_attributes() {
+        let mut node = Node::map();
+        node.insert(&path(&["method"]), Value::from("GET"));
+        node.insert(
+            &path(&["headers"]),
+            Value::from(HashMap::from([("a", "b")])),
+        );
+        let mut ctx = Context::default();
+        ctx.add_variable_from_value("request", node.into_value());
+        let expression =
+            cel_parser::parse("request.method == 'GET' && request.headers.a == 'b'").unwrap();
+        assert_eq!(Value::resolve(&expression, &ctx), Ok(Value::Bool(true)));
+    }
+}
@@ -0,0 +1,176 @@
+// Sets of IP address ranges, in CIDR notation (e.g. `10.0.0.0/8`, `fd00::/8`).
+//
+// Ranges are indexed by prefix length, so a lookup costs one hash lookup per distinct
+// prefix length of the set, regardless of the number of ranges.
+//
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+
+#[derive(Debug, Clone, Default)]
+pub struct CidrSet {
+    // network addresses by prefix length
+    v4: HashMap<u8, HashSet<u32>>,
+    v6: HashMap<u8, HashSet<u128>>,
+}
+
+impl CidrSet {
+    pub fn insert(&mut self, cidr: &str) -> Result<(), String> {
+        let (address, prefix_len) = match cidr.split_once('/') {
+            Some((address, prefix_len)) => (
+                address,
+                Some(
+                    prefix_len
+                        .parse::<u8>()
+                        .map_err(|_| format!("invalid CIDR `{cidr}`"))?,
+                ),
+            ),
+            None => (cidr, None),
+        };
+        match address
+            .parse::<IpAddr>()
+            .map_err(|_| format!("invalid CIDR `{cidr}`"))?
+        {
+            IpAddr::V4(address) => {
+                let prefix_len = prefix_len.unwrap_or(32);
+                if prefix_len > 32 {
+                    return Err(format!("invalid CIDR `{cidr}`: prefix longer than 32 bits"));
+                }
+                self.v4
+                    .entry(prefix_len)
+                    .or_default()
+                    .insert(u32::from(address) & mask_v4(prefix_len));
+            }
+            IpAddr::V6(address) => {
+                let prefix_len = prefix_len.unwrap_or(128);
+                if prefix_len > 128 {
+                    return Err(format!(
+                        "invalid CIDR `{cidr}`: prefix longer than 128 bits"
+                    ));
+                }
+                self.v6
+                    .entry(prefix_len)
+                    .or_default()
+                    .insert(u128::from(address) & mask_v6(prefix_len));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn contains(&self, address: IpAddr) -> bool {
+        // IPv4 addresses mapped to IPv6, e.g. `::ffff:10.0.0.1`, are matched as IPv4
+        match address.to_canonical() {
+            IpAddr::V4(address) => {
+                let address = u32::from(address);
+                self.v4.iter().any(|(prefix_len, networks)| {
+                    networks.contains(&(address & mask_v4(*prefix_len)))
+                })
+            }
+            IpAddr::V6(address) => {
+                let address = u128::from(address);
+                self.v6.iter().any(|(prefix_len, networks)| {
+                    networks.contains(&(address & mask_v6(*prefix_len)))
+                })
+            }
+        }
+    }
+}
+
+impl TryFrom<&[&str]> for CidrSet {
+    type Error = String;
+
+    fn try_from(cidrs: &[&str]) -> Result<Self, Self::Error> {
+        let mut set = CidrSet::default();
+        for cidr in cidrs {
+            set.insert(cidr)?;
+        }
+        Ok(set)
+    }
+}
+
+// Addresses as exposed by Envoy, with or without a port, e.g. `10.0.0.1:5432` or `[::1]:5432`
+pub fn parse_address(address: &str) -> Result<IpAddr, String> {
+    address
+        .parse::<SocketAddr>()
+        .map(|socket_address| socket_address.ip())
+        .or_else(|_| address.parse::<IpAddr>())
+        .map_err(|_| format!("invalid IP address `{address}`"))
+}
+
+fn mask_v4(prefix_len: u8) -> u32 {
+    u32::MAX
+        .checked_shl(32 - u32::from(prefix_len))
+        .unwrap_or(0)
+}
+
+fn mask_v6(prefix_len: u8) -> u128 {
+    u128::MAX
+        .checked_shl(128 - u32::from(prefix_len))
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cidr_set_contains() {
+        let set = CidrSet::try_from(
+            [
+                "10.0.0.0/8",
+                "192.168.1.0/24",
+                "203.0.113.7",
+                "fd00::/8",
+                "0.0.0.0/0",
+            ]
+            .as_slice(),
+        )
+        .unwrap();
+        let fixtures = [
+            ("10.1.2.3", true),
+            ("192.168.1.255", true),
+            ("192.168.2.1", true),
+            ("fd12::1", true),
+            ("::ffff:10.0.0.1", true),
+            ("2001:db8::1", false),
+        ];
+        for (address, expected) in fixtures {
+            assert_eq!(
+                set.contains(address.parse().unwrap()),
+                expected,
+                "{address}"
+            );
+        }
+
+        let set = CidrSet::try_from(["192.168.1.0/24", "203.0.113.7"].as_slice()).unwrap();
+        assert!(!set.contains("192.168.2.1".parse().unwrap()));
+        assert!(set.contains("203.0.113.7".parse().unwrap()));
+        assert!(!set.contains("203.0.113.8".parse().unwrap()));
+    }
+
+    #[test]
+    fn invalid_cidrs() {
+        for cidr in [
+            "10.0.0.0/33",
+            "fd00::/129",
+            "10.0.0/8",
+            "10.0.0.0/x",
+            "example.com",
+        ] {
+            assert!(CidrSet::default().insert(cidr).is_err(), "{cidr}");
+        }
+    }
+
+    #[test]
+    fn addresses_with_ports() {
+        assert_eq!(
+            parse_address("10.0.0.1:5432"),
+            Ok("10.0.0.1".parse().unwrap())
+        );
+        assert_eq!(
+            parse_address("[fd00::1]:5432"),
+            Ok("fd00::1".parse().unwrap())
+        );
+        assert_eq!(parse_address("fd00::1"), Ok("fd00::1".parse().unwrap()));
+        assert!(parse_address("localhost:80").is_err());
+    }
+}
@@ -1,14 +1,24 @@
 use std::cell::OnceCell;
+use std::collections::HashMap;
 use std::fmt::{Debug, Display, Formatter};
+use std::rc::Rc;
 use std::sync::Arc;
+use std::time::Duration;
 
 use cel_interpreter::objects::ValueType;
 use cel_interpreter::{Context, Expression, Value};
-use cel_parser::{Atom, RelationOp};
-use serde::Deserialize;
-
-use crate::attribute::Attribute;
-use crate::policy::Policy;
+use cel_parser::{Atom, RelationOp, UnaryOp};
+use log::warn;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Deserializer};
+
+use crate::attribute::cel_value;
+use crate::authority::Authority;
+use crate::cel::Program;
+use crate::cidr::{parse_address, CidrSet};
+use crate::glob::GlobPattern;
+use crate::heap_size::HeapSize;
+use crate::policy::{DenyResponse, Policy};
 use crate::policy_index::PolicyIndex;
 
 #[derive(Deserialize, Debug, Clone)]
@@ -26,12 +36,36 @@ pub struct SelectorItem {
     #[serde(default)]
     pub default: Option<String>,
 
+    // How the value is rendered in the descriptor.
+    // If not set, the canonical form of its type is used, e.g. RFC 3339 for timestamps.
+    #[serde(default)]
+    pub format: Option<ValueFormat>,
+
+    // Applied to the value of the attribute, in order, before it is placed in the descriptor
+    #[serde(default)]
+    pub normalize: Vec<Normalization>,
+
     #[serde(skip_deserializing)]
     path: OnceCell<Path>,
 }
 
 impl SelectorItem {
     pub fn compile(&self) -> Result<(), String> {
+        if let Some(format) = &self.format {
+            format
+                .validate(type_of(&self.selector))
+                .map_err(|e| format!("selector `{}`: {e}", self.selector))?;
+        }
+        if !self.normalize.is_empty() {
+            if self.format.is_some() {
+                return Err(format!(
+                    "selector `{}`: values can't be both normalized and formatted",
+                    self.selector
+                ));
+            }
+            Normalization::validate(type_of(&self.selector))
+                .map_err(|e| format!("selector `{}`: {e}", self.selector))?;
+        }
         self.path
             .set(self.selector.as_str().into())
             .map_err(|p| format!("Err on {p:?}"))
@@ -94,18 +128,133 @@ impl Path {
     }
 }
 
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueFormat {
+    // Timestamps
+    Rfc3339,
+    // Timestamps, as seconds since the epoch
+    Unix,
+    // Bytes and strings
+    Base64,
+    Hex,
+}
+
+impl ValueFormat {
+    fn validate(&self, cel_type: Option<ValueType>) -> Result<(), String> {
+        match (self, cel_type) {
+            (ValueFormat::Rfc3339 | ValueFormat::Unix, Some(ValueType::Timestamp)) => Ok(()),
+            (
+                ValueFormat::Base64 | ValueFormat::Hex,
+                None | Some(ValueType::String) | Some(ValueType::Bytes),
+            ) => Ok(()),
+            (format, cel_type) => Err(format!(
+                "format {format:?} does not apply to values of type `{}`",
+                cel_type.unwrap_or(ValueType::String)
+            )),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Normalization {
+    Trim,
+    Lowercase,
+    // e.g. `/caf%C3%A9` is `/café`
+    PercentDecode,
+}
+
+impl HeapSize for Normalization {
+    fn heap_size(&self) -> usize {
+        0
+    }
+}
+
+impl Normalization {
+    fn validate(cel_type: Option<ValueType>) -> Result<(), String> {
+        match cel_type {
+            None | Some(ValueType::String) | Some(ValueType::Bytes) => Ok(()),
+            Some(cel_type) => Err(format!("values of type `{cel_type}` can't be normalized")),
+        }
+    }
+
+    pub fn apply_all(normalizations: &[Normalization], value: String) -> String {
+        normalizations
+            .iter()
+            .fold(value, |value, normalization| normalization.apply(&value))
+    }
+
+    fn apply(&self, value: &str) -> String {
+        match self {
+            Normalization::Trim => value.trim().to_string(),
+            Normalization::Lowercase => value.to_lowercase(),
+            Normalization::PercentDecode => percent_decode(value),
+        }
+    }
+}
+
+// Invalid escape sequences are kept as is
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| value.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct StaticItem {
     pub value: String,
     pub key: String,
 }
 
+#[derive(Deserialize, Debug, Clone)]
+pub struct ExpressionItem {
+    pub key: String,
+    // CEL expression computing the value, e.g. `'user:' + auth.identity.tenant`
+    pub value: String,
+
+    #[serde(skip_deserializing)]
+    program: OnceCell<Program>,
+}
+
+impl ExpressionItem {
+    pub fn compile(&self) -> Result<(), String> {
+        self.program
+            .set(Program::compile(&self.value)?)
+            .map_err(|_| format!("expression `{}` compiled twice", self.value))
+    }
+
+    pub fn program(&self) -> &Program {
+        self.program
+            .get()
+            .expect("ExpressionItem wasn't previously compiled!")
+    }
+}
+
 // Mutually exclusive struct fields
 #[derive(Deserialize, Debug, Clone)]
 #[serde(rename_all = "lowercase")]
 pub enum DataType {
     Static(StaticItem),
     Selector(SelectorItem),
+    Expression(ExpressionItem),
 }
 
 impl DataType {
@@ -113,6 +262,7 @@ impl DataType {
         match self {
             DataType::Static(_) => Ok(()),
             DataType::Selector(selector) => selector.compile(),
+            DataType::Expression(expression) => expression.compile(),
         }
     }
 }
@@ -124,6 +274,27 @@ pub struct DataItem {
     pub item: DataType,
 }
 
+// Paths and programs are counted as the selector or the expression they're compiled from
+impl HeapSize for DataItem {
+    fn heap_size(&self) -> usize {
+        match &self.item {
+            DataType::Static(item) => item.key.heap_size() + item.value.heap_size(),
+            DataType::Selector(item) => {
+                item.selector.heap_size()
+                    + item.key.heap_size()
+                    + item.default.heap_size()
+                    + item.normalize.heap_size()
+                    + item.path.get().map_or(0, |_| item.selector.heap_size())
+            }
+            DataType::Expression(item) => {
+                item.key.heap_size()
+                    + item.value.heap_size()
+                    + item.program.get().map_or(0, |_| item.value.heap_size())
+            }
+        }
+    }
+}
+
 #[derive(Deserialize, PartialEq, Debug, Clone)]
 pub enum WhenConditionOperator {
     #[serde(rename = "eq")]
@@ -136,6 +307,60 @@ pub enum WhenConditionOperator {
     EndsWith,
     #[serde(rename = "matches")]
     Matches,
+    #[serde(rename = "contains")]
+    Contains,
+    #[serde(rename = "gt")]
+    GreaterThan,
+    #[serde(rename = "gte")]
+    GreaterOrEqual,
+    #[serde(rename = "lt")]
+    LessThan,
+    #[serde(rename = "lte")]
+    LessOrEqual,
+    #[serde(rename = "in")]
+    In,
+    #[serde(rename = "notin")]
+    NotIn,
+    #[serde(rename = "exists")]
+    Exists,
+    #[serde(rename = "notexists")]
+    NotExists,
+    #[serde(rename = "glob")]
+    Glob,
+    #[serde(rename = "notglob")]
+    NotGlob,
+    #[serde(rename = "incidr")]
+    InCidr,
+    #[serde(rename = "notincidr")]
+    NotInCidr,
+}
+
+impl WhenConditionOperator {
+    fn is_ordering(&self) -> bool {
+        matches!(
+            self,
+            WhenConditionOperator::GreaterThan
+                | WhenConditionOperator::GreaterOrEqual
+                | WhenConditionOperator::LessThan
+                | WhenConditionOperator::LessOrEqual
+        )
+    }
+
+    // Operators evaluated with a precompiled matcher, negating its outcome
+    fn is_negated(&self) -> bool {
+        matches!(
+            self,
+            WhenConditionOperator::NotGlob | WhenConditionOperator::NotInCidr
+        )
+    }
+
+    // Operators only checking whether the attribute is present, regardless of its value
+    fn is_presence(&self) -> bool {
+        matches!(
+            self,
+            WhenConditionOperator::Exists | WhenConditionOperator::NotExists
+        )
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -143,7 +368,14 @@ pub enum WhenConditionOperator {
 pub struct PatternExpression {
     pub selector: String,
     pub operator: WhenConditionOperator,
-    pub value: String,
+    // Only optional for the `exists` and `notexists` operators
+    pub value: Option<String>,
+    // Compare strings regardless of their case
+    #[serde(default)]
+    pub ignore_case: bool,
+    // Applied to the value of the attribute, in order, before the comparison
+    #[serde(default)]
+    pub normalize: Vec<Normalization>,
 
     #[serde(skip_deserializing)]
     path: OnceCell<Path>,
@@ -153,6 +385,12 @@ pub struct PatternExpression {
 
 impl PatternExpression {
     pub fn compile(&self) -> Result<(), String> {
+        if self.value.is_none() && !self.operator.is_presence() {
+            return Err(format!(
+                "condition on `{}` without a value to compare to",
+                self.selector
+            ));
+        }
         self.path
             .set(self.selector.as_str().into())
             .map_err(|_| "Duh!")?;
@@ -167,21 +405,46 @@ impl PatternExpression {
             .tokens()
     }
 
+    pub fn value(&self) -> &str {
+        self.value.as_deref().unwrap_or_default()
+    }
+
+    pub fn checks_presence(&self) -> bool {
+        self.operator.is_presence()
+    }
+
+    // Evaluates the pattern on the attribute, `None` when the request does not have it
+    pub fn applies(&self, raw_attribute: Option<Vec<u8>>) -> Result<bool, String> {
+        match raw_attribute {
+            Some(raw_attribute) => self.eval(raw_attribute),
+            None if self.operator.is_presence() => {
+                Ok(self.operator == WhenConditionOperator::NotExists)
+            }
+            // missing attributes default to ``
+            None => self.eval(Vec::new()),
+        }
+    }
+
     pub fn eval(&self, raw_attribute: Vec<u8>) -> Result<bool, String> {
-        let cel_type = &self.compiled.get().unwrap().cel_type;
-        let value = match cel_type {
-            ValueType::String => Value::String(Arc::new(Attribute::parse(raw_attribute)?)),
-            ValueType::Int => Value::Int(Attribute::parse(raw_attribute)?),
-            ValueType::UInt => Value::UInt(Attribute::parse(raw_attribute)?),
-            ValueType::Float => Value::Float(Attribute::parse(raw_attribute)?),
-            ValueType::Bytes => Value::Bytes(Arc::new(Attribute::parse(raw_attribute)?)),
-            ValueType::Bool => Value::Bool(Attribute::parse(raw_attribute)?),
-            ValueType::Timestamp => Value::Timestamp(Attribute::parse(raw_attribute)?),
-            // todo: Impl support for parsing these two types… Tho List/Map of what?
-            // ValueType::List => {}
-            // ValueType::Map => {}
-            _ => unimplemented!("Need support for {}", cel_type),
+        if self.operator.is_presence() {
+            return Ok(self.operator == WhenConditionOperator::Exists);
+        }
+        let compiled = self.compiled.get().unwrap();
+        let value = match cel_value(&compiled.cel_type, raw_attribute)? {
+            Value::String(string) if !compiled.normalize.is_empty() => Value::String(
+                Normalization::apply_all(&compiled.normalize, string.to_string()).into(),
+            ),
+            value => value,
         };
+        if let (Some(matcher), Value::String(string)) =
+            (&self.compiled.get().unwrap().matcher, &value)
+        {
+            let matched = match matcher {
+                Matcher::Regex(regex) => regex.is_match(string),
+                Matcher::Cidrs(cidrs) => cidrs.contains(parse_address(string)?),
+            };
+            return Ok(matched != self.operator.is_negated());
+        }
         let mut ctx = Context::default();
         ctx.add_variable_from_value("attribute", value);
         Value::resolve(&self.compiled.get().unwrap().expression, &ctx)
@@ -196,9 +459,34 @@ impl PatternExpression {
     }
 }
 
+// The path and the compiled expression are counted as the selector and the value they're
+// built from
+impl HeapSize for PatternExpression {
+    fn heap_size(&self) -> usize {
+        let source = self.selector.heap_size() + self.value.heap_size();
+        source
+            + self.normalize.heap_size()
+            + self.path.get().map_or(0, |_| self.selector.heap_size())
+            + self
+                .compiled
+                .get()
+                .map_or(0, |compiled| source + compiled.normalize.heap_size())
+    }
+}
+
 struct CelExpression {
     expression: Expression,
     cel_type: ValueType,
+    // Compiled once, evaluated in place of the expression
+    matcher: Option<Matcher>,
+    // Applied to the attribute, including lowercasing when ignoring the case
+    normalize: Vec<Normalization>,
+}
+
+#[derive(Clone)]
+enum Matcher {
+    Regex(Regex),
+    Cidrs(CidrSet),
 }
 
 impl Debug for CelExpression {
@@ -211,6 +499,8 @@ impl Clone for CelExpression {
     fn clone(&self) -> Self {
         Self {
             expression: self.expression.clone(),
+            matcher: self.matcher.clone(),
+            normalize: self.normalize.clone(),
             cel_type: match self.cel_type {
                 ValueType::List => ValueType::List,
                 ValueType::Map => ValueType::Map,
@@ -233,30 +523,75 @@ impl TryFrom<&PatternExpression> for CelExpression {
     type Error = String;
 
     fn try_from(expression: &PatternExpression) -> Result<Self, Self::Error> {
-        let cel_value = match cel_parser::parse(&expression.value) {
+        let cel_value = match cel_parser::parse(expression.value()) {
             Ok(exp) => match exp {
                 Expression::Ident(ident) => Expression::Atom(Atom::String(ident)),
                 Expression::Member(_, _) => {
-                    Expression::Atom(Atom::String(expression.value.to_string().into()))
+                    Expression::Atom(Atom::String(expression.value().to_string().into()))
                 }
                 _ => exp,
             },
-            Err(_) => Expression::Atom(Atom::String(expression.value.clone().into())),
+            Err(_) => Expression::Atom(Atom::String(expression.value().to_owned().into())),
+        };
+        let cel_type = match type_of(&expression.selector) {
+            Some(cel_type) => cel_type,
+            None => match &cel_value {
+                Expression::List(_) => ValueType::List,
+                Expression::Map(_) => ValueType::Map,
+                Expression::Atom(atom) => match atom {
+                    Atom::Int(_) => ValueType::Int,
+                    Atom::UInt(_) => ValueType::UInt,
+                    Atom::Float(_) => ValueType::Float,
+                    Atom::String(_) => ValueType::String,
+                    Atom::Bytes(_) => ValueType::Bytes,
+                    Atom::Bool(_) => ValueType::Bool,
+                    Atom::Null => ValueType::Null,
+                },
+                _ => return Err(format!("Unsupported CEL value: {cel_value:?}")),
+            },
         };
-        let cel_type = type_of(&expression.selector).unwrap_or(match &cel_value {
-            Expression::List(_) => Ok(ValueType::List),
-            Expression::Map(_) => Ok(ValueType::Map),
-            Expression::Atom(atom) => Ok(match atom {
-                Atom::Int(_) => ValueType::Int,
-                Atom::UInt(_) => ValueType::UInt,
-                Atom::Float(_) => ValueType::Float,
-                Atom::String(_) => ValueType::String,
-                Atom::Bytes(_) => ValueType::Bytes,
-                Atom::Bool(_) => ValueType::Bool,
-                Atom::Null => ValueType::Null,
-            }),
-            _ => Err(format!("Unsupported CEL value: {cel_value:?}")),
-        }?);
+
+        if expression.operator.is_presence() {
+            return Ok(Self {
+                expression: Expression::Atom(Atom::Bool(
+                    expression.operator == WhenConditionOperator::Exists,
+                )),
+                cel_type,
+                matcher: None,
+                normalize: Vec::new(),
+            });
+        }
+
+        let mut normalize = expression.normalize.clone();
+        if expression.ignore_case {
+            normalize.push(Normalization::Lowercase);
+        }
+        if !normalize.is_empty() && !matches!(cel_type, ValueType::String) {
+            return Err(format!(
+                "selector `{}`: only strings can be normalized or compared ignoring the case",
+                expression.selector
+            ));
+        }
+
+        // Addresses are only ever checked by the matcher, the expression is a mere placeholder
+        if let WhenConditionOperator::InCidr | WhenConditionOperator::NotInCidr =
+            expression.operator
+        {
+            if !matches!(cel_type, ValueType::String) {
+                return Err(format!(
+                    "Unsupported operator {:?} on {cel_type}",
+                    &expression.operator
+                ));
+            }
+            return Ok(Self {
+                expression: Expression::Atom(Atom::Bool(false)),
+                cel_type,
+                matcher: Some(Matcher::Cidrs(CidrSet::try_from(
+                    cidr_list(expression.value()).as_slice(),
+                )?)),
+                normalize,
+            });
+        }
 
         let value = match cel_type {
             ValueType::Map => match expression.operator {
@@ -267,32 +602,62 @@ impl TryFrom<&PatternExpression> for CelExpression {
                         Err(format!("Can't compare {cel_value:?} with a Map"))
                     }
                 }
+                // the value is a key of the map
+                WhenConditionOperator::Contains => Ok(string_value(cel_value, expression)),
                 _ => Err(format!(
                     "Unsupported operator {:?} on Map",
                     &expression.operator
                 )),
             },
-            ValueType::Int | ValueType::UInt | ValueType::Float => match expression.operator {
+            ValueType::List => match expression.operator {
                 WhenConditionOperator::Equal | WhenConditionOperator::NotEqual => {
-                    if let Expression::Atom(atom) = &cel_value {
-                        match atom {
-                            Atom::Int(_) | Atom::UInt(_) | Atom::Float(_) => Ok(cel_value),
-                            _ => Err(format!("Can't compare {cel_value:?} with a Number")),
-                        }
+                    if let Expression::List(items) = cel_value {
+                        Ok(Expression::List(items))
                     } else {
-                        Err(format!("Can't compare {cel_value:?} with a Number"))
+                        Err(format!("Can't compare {cel_value:?} with a List"))
                     }
                 }
+                // the value is an item of the list
+                WhenConditionOperator::Contains => Ok(string_value(cel_value, expression)),
+                _ => Err(format!(
+                    "Unsupported operator {:?} on List",
+                    &expression.operator
+                )),
+            },
+            ValueType::Int | ValueType::UInt | ValueType::Float => match expression.operator {
+                WhenConditionOperator::Equal
+                | WhenConditionOperator::NotEqual
+                | WhenConditionOperator::GreaterThan
+                | WhenConditionOperator::GreaterOrEqual
+                | WhenConditionOperator::LessThan
+                | WhenConditionOperator::LessOrEqual => number_value(cel_value, expression),
+                WhenConditionOperator::In | WhenConditionOperator::NotIn => {
+                    list_value(cel_value, |item| match item {
+                        Expression::Atom(Atom::Int(_) | Atom::UInt(_) | Atom::Float(_)) => Ok(item),
+                        _ => Err(format!("Can't compare {item:?} with a Number")),
+                    })
+                }
                 _ => Err(format!(
                     "Unsupported operator {:?} on Number",
                     &expression.operator
                 )),
             },
-            ValueType::String => match &cel_value {
-                Expression::Atom(Atom::String(_)) => Ok(cel_value),
-                _ => Ok(Expression::Atom(Atom::String(Arc::new(
-                    expression.value.clone(),
-                )))),
+            ValueType::String => match expression.operator {
+                WhenConditionOperator::In | WhenConditionOperator::NotIn => {
+                    list_value(cel_value, |item| match item {
+                        Expression::Atom(Atom::String(_)) => Ok(item),
+                        Expression::Ident(ident) => Ok(Expression::Atom(Atom::String(ident))),
+                        Expression::Atom(Atom::Int(int)) => {
+                            Ok(Expression::Atom(Atom::String(int.to_string().into())))
+                        }
+                        _ => Err(format!("Can't compare {item:?} with a String")),
+                    })
+                }
+                _ if expression.operator.is_ordering() => Err(format!(
+                    "Unsupported operator {:?} on String",
+                    &expression.operator
+                )),
+                _ => Ok(string_value(cel_value, expression)),
             },
             ValueType::Bytes => match expression.operator {
                 WhenConditionOperator::Equal | WhenConditionOperator::NotEqual => {
@@ -328,7 +693,12 @@ impl TryFrom<&PatternExpression> for CelExpression {
                 )),
             },
             ValueType::Timestamp => match expression.operator {
-                WhenConditionOperator::Equal | WhenConditionOperator::NotEqual => {
+                WhenConditionOperator::Equal
+                | WhenConditionOperator::NotEqual
+                | WhenConditionOperator::GreaterThan
+                | WhenConditionOperator::GreaterOrEqual
+                | WhenConditionOperator::LessThan
+                | WhenConditionOperator::LessOrEqual => {
                     if let Expression::Atom(atom) = &cel_value {
                         match atom {
                             Atom::String(_) => Ok(Expression::FunctionCall(
@@ -352,6 +722,33 @@ impl TryFrom<&PatternExpression> for CelExpression {
             )),
         }?;
 
+        // regular expressions are matched ignoring the case instead
+        let value = match expression.operator {
+            WhenConditionOperator::Matches => value,
+            _ if expression.ignore_case => lowercase(value),
+            _ => value,
+        };
+
+        let matcher = match (&expression.operator, &value) {
+            (
+                WhenConditionOperator::Glob | WhenConditionOperator::NotGlob,
+                Expression::Atom(Atom::String(pattern)),
+            ) => Some(Matcher::Regex(
+                GlobPattern::with_separator(pattern, '/')
+                    .map_err(|e| format!("invalid glob pattern `{pattern}`: {e}"))?
+                    .into_inner(),
+            )),
+            (WhenConditionOperator::Matches, Expression::Atom(Atom::String(pattern))) => {
+                Some(Matcher::Regex(
+                    RegexBuilder::new(pattern)
+                        .case_insensitive(expression.ignore_case)
+                        .build()
+                        .map_err(|e| format!("invalid regular expression `{pattern}`: {e}"))?,
+                ))
+            }
+            _ => None,
+        };
+
         let expression = match expression.operator {
             WhenConditionOperator::Equal => Expression::Relation(
                 Expression::Ident(Arc::new("attribute".to_string())).into(),
@@ -376,17 +773,155 @@ impl TryFrom<&PatternExpression> for CelExpression {
             WhenConditionOperator::Matches => Expression::FunctionCall(
                 Expression::Ident(Arc::new("matches".to_string())).into(),
                 Some(Expression::Ident("attribute".to_string().into()).into()),
+                [regex_value(matcher.as_ref())].to_vec(),
+            ),
+            WhenConditionOperator::Contains => Expression::FunctionCall(
+                Expression::Ident(Arc::new("contains".to_string())).into(),
+                Some(Expression::Ident("attribute".to_string().into()).into()),
                 [value].to_vec(),
             ),
+            WhenConditionOperator::GreaterThan => Expression::Relation(
+                Expression::Ident(Arc::new("attribute".to_string())).into(),
+                RelationOp::GreaterThan,
+                value.into(),
+            ),
+            WhenConditionOperator::GreaterOrEqual => Expression::Relation(
+                Expression::Ident(Arc::new("attribute".to_string())).into(),
+                RelationOp::GreaterThanEq,
+                value.into(),
+            ),
+            WhenConditionOperator::LessThan => Expression::Relation(
+                Expression::Ident(Arc::new("attribute".to_string())).into(),
+                RelationOp::LessThan,
+                value.into(),
+            ),
+            WhenConditionOperator::LessOrEqual => Expression::Relation(
+                Expression::Ident(Arc::new("attribute".to_string())).into(),
+                RelationOp::LessThanEq,
+                value.into(),
+            ),
+            WhenConditionOperator::In => Expression::Relation(
+                Expression::Ident(Arc::new("attribute".to_string())).into(),
+                RelationOp::In,
+                value.into(),
+            ),
+            WhenConditionOperator::NotIn => Expression::Unary(
+                UnaryOp::Not,
+                Expression::Relation(
+                    Expression::Ident(Arc::new("attribute".to_string())).into(),
+                    RelationOp::In,
+                    value.into(),
+                )
+                .into(),
+            ),
+            WhenConditionOperator::Glob => Expression::FunctionCall(
+                Expression::Ident(Arc::new("matches".to_string())).into(),
+                Some(Expression::Ident("attribute".to_string().into()).into()),
+                [regex_value(matcher.as_ref())].to_vec(),
+            ),
+            WhenConditionOperator::NotGlob => Expression::Unary(
+                UnaryOp::Not,
+                Expression::FunctionCall(
+                    Expression::Ident(Arc::new("matches".to_string())).into(),
+                    Some(Expression::Ident("attribute".to_string().into()).into()),
+                    [regex_value(matcher.as_ref())].to_vec(),
+                )
+                .into(),
+            ),
+            WhenConditionOperator::Exists
+            | WhenConditionOperator::NotExists
+            | WhenConditionOperator::InCidr
+            | WhenConditionOperator::NotInCidr => {
+                unreachable!("presence and CIDR operators are compiled to constants")
+            }
         };
 
         Ok(Self {
             expression,
             cel_type,
+            matcher,
+            normalize,
         })
     }
 }
 
+// Map keys and list items are strings, e.g. `x-tier` is not the CEL expression `x - tier`
+fn string_value(cel_value: Expression, expression: &PatternExpression) -> Expression {
+    match cel_value {
+        Expression::Atom(Atom::String(_)) => cel_value,
+        _ => Expression::Atom(Atom::String(Arc::new(expression.value().to_owned()))),
+    }
+}
+
+// The pattern of a precompiled regex, as a CEL string
+fn regex_value(matcher: Option<&Matcher>) -> Expression {
+    let Some(Matcher::Regex(regex)) = matcher else {
+        unreachable!("the regex was compiled along the expression")
+    };
+    Expression::Atom(Atom::String(regex.as_str().to_string().into()))
+}
+
+fn lowercase(value: Expression) -> Expression {
+    match value {
+        Expression::Atom(Atom::String(string)) => {
+            Expression::Atom(Atom::String(string.to_lowercase().into()))
+        }
+        Expression::List(items) => Expression::List(items.into_iter().map(lowercase).collect()),
+        value => value,
+    }
+}
+
+// e.g. `[10.0.0.0/8, 192.168.0.0/16]`, brackets and quotes being optional
+fn cidr_list(value: &str) -> Vec<&str> {
+    let value = value.trim();
+    let value = value
+        .strip_prefix('[')
+        .and_then(|value| value.strip_suffix(']'))
+        .unwrap_or(value);
+    value
+        .split(',')
+        .map(|cidr| cidr.trim().trim_matches(['\'', '"']))
+        .filter(|cidr| !cidr.is_empty())
+        .collect()
+}
+
+// Numbers may be given in bytes with a binary unit, e.g. `1MiB`
+fn number_value(
+    cel_value: Expression,
+    expression: &PatternExpression,
+) -> Result<Expression, String> {
+    match cel_value {
+        Expression::Atom(Atom::Int(_) | Atom::UInt(_) | Atom::Float(_)) => Ok(cel_value),
+        _ => parse_size(expression.value())
+            .map(|size| Expression::Atom(Atom::Int(size)))
+            .ok_or_else(|| format!("Can't compare {cel_value:?} with a Number")),
+    }
+}
+
+fn parse_size(value: &str) -> Option<i64> {
+    let value = value.trim();
+    let (number, multiplier) = [("KiB", 1 << 10), ("MiB", 1 << 20), ("GiB", 1 << 30)]
+        .into_iter()
+        .find_map(|(unit, multiplier)| Some((value.strip_suffix(unit)?, multiplier)))?;
+    number.trim().parse::<i64>().ok()?.checked_mul(multiplier)
+}
+
+// The items of a list literal, e.g. `[GET, HEAD]`
+fn list_value<F>(cel_value: Expression, item_value: F) -> Result<Expression, String>
+where
+    F: Fn(Expression) -> Result<Expression, String>,
+{
+    match cel_value {
+        Expression::List(items) => Ok(Expression::List(
+            items
+                .into_iter()
+                .map(item_value)
+                .collect::<Result<_, _>>()?,
+        )),
+        _ => Err(format!("Expected a list, got {cel_value:?}")),
+    }
+}
+
 pub fn type_of(path: &str) -> Option<ValueType> {
     match path {
         "request.time" => Some(ValueType::Timestamp),
@@ -433,6 +968,8 @@ pub fn type_of(path: &str) -> Option<ValueType> {
         "connection.mtls" => Some(ValueType::Bool),
         "request.raw_body" => Some(ValueType::Bytes),
         "auth.identity" => Some(ValueType::Bytes),
+        // values from the auth response are exposed as strings
+        _ if path.starts_with("auth.") => Some(ValueType::String),
         _ => None,
     }
 }
@@ -440,14 +977,30 @@ pub fn type_of(path: &str) -> Option<ValueType> {
 pub struct FilterConfig {
     pub index: PolicyIndex,
     // Deny/Allow request when faced with an irrecoverable failure.
-    pub failure_mode: FailureMode,
+    pub failure_mode: FailureModes,
+    // Response to rate limited requests of policies not setting their own
+    pub deny_response: DenyResponse,
+    pub rate_limit_headers: RateLimitHeaders,
+    // Services by name, including the clusters referenced directly by the policies
+    pub services: HashMap<String, Service>,
+}
+
+impl FilterConfig {
+    pub fn service(&self, name: &str) -> &Service {
+        self.services
+            .get(name)
+            .expect("service of a policy wasn't registered!")
+    }
 }
 
 impl Default for FilterConfig {
     fn default() -> Self {
         Self {
             index: PolicyIndex::new(),
-            failure_mode: FailureMode::Deny,
+            failure_mode: FailureModes::all(FailureMode::Deny),
+            deny_response: DenyResponse::default(),
+            rate_limit_headers: RateLimitHeaders::None,
+            services: HashMap::new(),
         }
     }
 }
@@ -456,56 +1009,360 @@ impl TryFrom<PluginConfiguration> for FilterConfig {
     type Error = String;
 
     fn try_from(config: PluginConfiguration) -> Result<Self, Self::Error> {
-        let mut index = PolicyIndex::new();
-
-        for rlp in config.policies.iter() {
-            for rule in &rlp.rules {
-                for datum in &rule.data {
-                    let result = datum.item.compile();
-                    if result.is_err() {
-                        return Err(result.err().unwrap());
+        let mut index = PolicyIndex::with_wildcard_depth(config.hostname_wildcards);
+
+        config.deny_response.validate()?;
+
+        let mut services = config.services;
+        for (name, service) in services.iter() {
+            service
+                .validate()
+                .map_err(|e| format!("service `{name}`: {e}"))?;
+        }
+
+        // policies are shared by all their hostnames in the index
+        let policies: Vec<Rc<Policy>> = config.policies.into_iter().map(Rc::new).collect();
+        // policies claiming each hostname, conflicting when they are scoped to the same routes
+        let mut claims: HashMap<Authority, Vec<&Policy>> = HashMap::new();
+        for policy in policies.iter() {
+            let rlp: &Policy = policy;
+            rlp.compile()?;
+            register_service(&mut services, &rlp.service, ServiceKind::RateLimit)
+                .map_err(|e| format!("policy `{}`: {e}", rlp.name))?;
+            if let Some(auth) = &rlp.auth {
+                register_service(&mut services, &auth.service, ServiceKind::Auth)
+                    .map_err(|e| format!("policy `{}`: {e}", rlp.name))?;
+            }
+            for hostname in rlp.hostnames.iter() {
+                let authority =
+                    Authority::parse(hostname).expect("hostnames are validated along the policies");
+                let owners = claims.entry(authority).or_default();
+                let claim = owners.iter().find(|owner| owner.routes == rlp.routes);
+                match (claim, config.hostname_conflicts) {
+                    (None, _) => {}
+                    (Some(owner), HostnameConflicts::Reject) => {
+                        return Err(if std::ptr::eq(*owner, rlp) {
+                            format!(
+                                "policy `{}`: hostname `{hostname}` listed more than once",
+                                rlp.name
+                            )
+                        } else {
+                            format!(
+                                "hostname `{hostname}` claimed by both policies `{}` and `{}`",
+                                owner.name, rlp.name
+                            )
+                        });
                     }
-                }
-                for condition in &rule.conditions {
-                    for pe in &condition.all_of {
-                        let result = pe.compile();
-                        if result.is_err() {
-                            return Err(result.err().unwrap());
-                        }
+                    (Some(owner), _) if std::ptr::eq(*owner, rlp) => continue,
+                    (Some(owner), HostnameConflicts::FirstWins) => {
+                        warn!(
+                            "hostname `{hostname}` claimed by both policies `{}` and `{}`, ignoring the latter",
+                            owner.name, rlp.name
+                        );
+                        continue;
+                    }
+                    (Some(owner), HostnameConflicts::Merge) => {
+                        warn!(
+                            "hostname `{hostname}` claimed by both policies `{}` and `{}`, merging them",
+                            owner.name, rlp.name
+                        );
                     }
                 }
-            }
-            for hostname in rlp.hostnames.iter() {
-                index.insert(hostname, rlp.clone());
+                owners.push(rlp);
+                index.insert(hostname, Rc::clone(policy));
             }
         }
 
         Ok(Self {
             index,
             failure_mode: config.failure_mode,
+            deny_response: config.deny_response,
+            rate_limit_headers: config.rate_limit_headers,
+            services,
         })
     }
 }
 
-#[derive(Deserialize, Debug, Clone)]
+// Services not declared in the configuration are Envoy clusters referenced directly by the policies
+fn register_service(
+    services: &mut HashMap<String, Service>,
+    name: &str,
+    kind: ServiceKind,
+) -> Result<(), String> {
+    match services.get(name) {
+        Some(service) if service.kind != kind => Err(format!(
+            "service `{name}` is of kind {:?}, expected {kind:?}",
+            service.kind
+        )),
+        Some(_) => Ok(()),
+        None => {
+            services.insert(name.to_owned(), Service::cluster(name, kind));
+            Ok(())
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum FailureMode {
     Deny,
     Allow,
 }
 
+// Irrecoverable failures of the calls to the services
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Failure {
+    // The call could not be dispatched or failed, e.g. the cluster is unavailable
+    DispatchError,
+    Timeout,
+    // The response could not be parsed
+    MalformedResponse,
+    // The rate limit service answered with an `UNKNOWN` overall code
+    Unknown,
+}
+
+// Failure mode by kind of failure, either a single mode for all of them:
+//   failureMode: allow
+// or per failure, `default` applying to the ones not listed:
+//   failureMode: { timeout: allow, default: deny }
+// Failures without a mode fall back to the next level of configuration.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(from = "FailureModesConfig")]
+pub struct FailureModes {
+    pub dispatch_error: Option<FailureMode>,
+    pub timeout: Option<FailureMode>,
+    pub malformed_response: Option<FailureMode>,
+    pub unknown: Option<FailureMode>,
+}
+
+impl FailureModes {
+    pub fn all(failure_mode: FailureMode) -> Self {
+        FailureModes {
+            dispatch_error: Some(failure_mode),
+            timeout: Some(failure_mode),
+            malformed_response: Some(failure_mode),
+            unknown: Some(failure_mode),
+        }
+    }
+
+    pub fn get(&self, failure: Failure) -> Option<FailureMode> {
+        match failure {
+            Failure::DispatchError => self.dispatch_error,
+            Failure::Timeout => self.timeout,
+            Failure::MalformedResponse => self.malformed_response,
+            Failure::Unknown => self.unknown,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FailureModesConfig {
+    All(FailureMode),
+    PerFailure(PerFailureModes),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct PerFailureModes {
+    #[serde(default)]
+    default: Option<FailureMode>,
+    #[serde(default)]
+    dispatch_error: Option<FailureMode>,
+    #[serde(default)]
+    timeout: Option<FailureMode>,
+    #[serde(default)]
+    malformed_response: Option<FailureMode>,
+    #[serde(default)]
+    unknown: Option<FailureMode>,
+}
+
+impl From<FailureModesConfig> for FailureModes {
+    fn from(config: FailureModesConfig) -> Self {
+        match config {
+            FailureModesConfig::All(failure_mode) => FailureModes::all(failure_mode),
+            FailureModesConfig::PerFailure(modes) => FailureModes {
+                dispatch_error: modes.dispatch_error.or(modes.default),
+                timeout: modes.timeout.or(modes.default),
+                malformed_response: modes.malformed_response.or(modes.default),
+                unknown: modes.unknown.or(modes.default),
+            },
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ServiceKind {
+    #[serde(rename = "ratelimit")]
+    RateLimit,
+    #[serde(rename = "auth")]
+    Auth,
+}
+
+// gRPC status codes of a failed call worth retrying
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RetryOn {
+    Cancelled = 1,
+    Unknown = 2,
+    DeadlineExceeded = 4,
+    ResourceExhausted = 8,
+    Internal = 13,
+    Unavailable = 14,
+}
+
+const DEFAULT_SERVICE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Service {
+    pub kind: ServiceKind,
+    // Name of the Envoy cluster of the service
+    pub cluster: String,
+    // e.g. `200ms`, `1s`
+    #[serde(
+        default = "Service::default_timeout",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub timeout: Duration,
+    #[serde(default)]
+    pub max_retries: u32,
+    #[serde(default = "Service::default_retry_on")]
+    pub retry_on: Vec<RetryOn>,
+    // Overrides the failure mode of the plugin configuration
+    #[serde(default)]
+    pub failure_mode: FailureModes,
+}
+
+impl Service {
+    fn cluster(cluster: &str, kind: ServiceKind) -> Self {
+        Service {
+            kind,
+            cluster: cluster.to_owned(),
+            timeout: DEFAULT_SERVICE_TIMEOUT,
+            max_retries: 0,
+            retry_on: Self::default_retry_on(),
+            failure_mode: FailureModes::default(),
+        }
+    }
+
+    fn default_timeout() -> Duration {
+        DEFAULT_SERVICE_TIMEOUT
+    }
+
+    fn default_retry_on() -> Vec<RetryOn> {
+        vec![RetryOn::Unavailable]
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.cluster.is_empty() {
+            return Err("empty cluster name".to_string());
+        }
+        if self.timeout.is_zero() {
+            return Err("timeout must be greater than zero".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn should_retry(&self, grpc_status: u32, retries: u32) -> bool {
+        retries < self.max_retries
+            && self
+                .retry_on
+                .iter()
+                .any(|retry_on| *retry_on as u32 == grpc_status)
+    }
+}
+
+fn deserialize_duration<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let duration = String::deserialize(deserializer)?;
+    parse_duration(&duration).map_err(serde::de::Error::custom)
+}
+
+fn parse_duration(duration: &str) -> Result<Duration, String> {
+    let split = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(duration.len());
+    let (value, unit) = duration.split_at(split);
+    let value: u64 = value
+        .parse()
+        .map_err(|_| format!("invalid duration `{duration}`"))?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(value)),
+        "s" => Ok(Duration::from_secs(value)),
+        "m" => Ok(Duration::from_secs(value * 60)),
+        _ => Err(format!(
+            "invalid duration `{duration}`, expected a unit of `ms`, `s` or `m`"
+        )),
+    }
+}
+
+// Headers describing the quota of the most restrictive limit, added to the responses
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitHeaders {
+    #[default]
+    None,
+    // `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset`, as per the IETF draft
+    Draft,
+    // `X-RateLimit-Limit`, `X-RateLimit-Remaining` and `X-RateLimit-Reset`
+    Legacy,
+}
+
+// What to do with policies claiming the same hostname, on the same routes
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum HostnameConflicts {
+    // All the policies apply, as if their rules were merged
+    #[default]
+    Merge,
+    // The configuration is rejected
+    Reject,
+    // Only the first policy applies
+    FirstWins,
+}
+
+// How far below their domain `*.` wildcard hostnames reach
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum WildcardDepth {
+    // `*.example.com` matches `a.example.com` and `a.b.example.com`
+    #[default]
+    AnyDepth,
+    // `*.example.com` matches `a.example.com` only, as in the Gateway API
+    SingleLabel,
+}
+
 #[derive(Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct PluginConfiguration {
     #[serde(rename = "rateLimitPolicies")]
     pub policies: Vec<Policy>,
     // Deny/Allow request when faced with an irrecoverable failure.
-    pub failure_mode: FailureMode,
+    // Failures without a mode at any level are denied.
+    pub failure_mode: FailureModes,
+    // Response to rate limited requests, unless overridden by the policy
+    #[serde(default)]
+    pub deny_response: DenyResponse,
+    // Opt-in, `Retry-After` is added as well to rate limited requests
+    #[serde(default)]
+    pub rate_limit_headers: RateLimitHeaders,
+    // Services referenced by name by the policies
+    #[serde(default)]
+    pub services: HashMap<String, Service>,
+    #[serde(default)]
+    pub hostname_conflicts: HostnameConflicts,
+    #[serde(default)]
+    pub hostname_wildcards: WildcardDepth,
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::policy::{ActionKind, ConditionItem};
+    use crate::policy_index::unscoped;
 
     const CONFIG: &str = r#"{
         "failureMode": "deny",
@@ -668,6 +1525,66 @@ mod test {
         }
     }
 
+    #[test]
+    fn parse_config_data_expression() {
+        let config = r#"{
+            "failureMode": "deny",
+            "rateLimitPolicies": [
+            {
+                "name": "rlp-ns-A/rlp-name-A",
+                "domain": "rlp-ns-A/rlp-name-A",
+                "service": "limitador-cluster",
+                "hostnames": ["*.toystore.com"],
+                "rules": [
+                {
+                    "data": [
+                    {
+                        "expression": {
+                            "key": "user",
+                            "value": "'user:' + auth.identity.tenant.lowerAscii()"
+                        }
+                    }]
+                }]
+            }]
+        }"#;
+        let res = serde_json::from_str::<PluginConfiguration>(config);
+        if let Err(ref e) = res {
+            eprintln!("{e}");
+        }
+        assert!(res.is_ok());
+
+        let plugin_config = res.unwrap();
+        let data_items = &plugin_config.policies[0].rules[0].data;
+        if let DataType::Expression(expression_item) = &data_items[0].item {
+            assert_eq!(expression_item.key, "user");
+            assert_eq!(
+                expression_item.value,
+                "'user:' + auth.identity.tenant.lowerAscii()"
+            );
+        } else {
+            panic!();
+        }
+        assert!(FilterConfig::try_from(plugin_config).is_ok());
+
+        let config = r#"{
+            "failureMode": "deny",
+            "rateLimitPolicies": [
+            {
+                "name": "rlp-ns-A/rlp-name-A",
+                "domain": "rlp-ns-A/rlp-name-A",
+                "service": "limitador-cluster",
+                "hostnames": ["*.toystore.com"],
+                "rules": [
+                {
+                    "data": [ { "expression": { "key": "user", "value": "'user:' +" } } ]
+                }]
+            }]
+        }"#;
+        let res = serde_json::from_str::<PluginConfiguration>(config);
+        assert!(res.is_ok());
+        assert!(FilterConfig::try_from(res.unwrap()).is_err());
+    }
+
     #[test]
     fn parse_config_condition_selector_operators() {
         let config = r#"{
@@ -741,9 +1658,15 @@ mod test {
         ];
 
         for i in 0..expected_conditions.len() {
-            assert_eq!(all_of_conditions[i].selector, expected_conditions[i].0);
-            assert_eq!(all_of_conditions[i].value, expected_conditions[i].1);
-            assert_eq!(all_of_conditions[i].operator, expected_conditions[i].2);
+            let ConditionItem::Pattern(pattern) = &all_of_conditions[i] else {
+                panic!(
+                    "Expected a pattern expression, got {:?}",
+                    all_of_conditions[i]
+                );
+            };
+            assert_eq!(pattern.selector, expected_conditions[i].0);
+            assert_eq!(pattern.value(), expected_conditions[i].1);
+            assert_eq!(pattern.operator, expected_conditions[i].2);
         }
     }
 
@@ -790,6 +1713,45 @@ mod test {
         assert_eq!(conditions.len(), 0);
     }
 
+    #[test]
+    fn parse_config_auth() {
+        let config = r#"{
+            "failureMode": "deny",
+            "rateLimitPolicies": [
+            {
+                "name": "rlp-ns-A/rlp-name-A",
+                "domain": "rlp-ns-A/rlp-name-A",
+                "service": "limitador-cluster",
+                "hostnames": ["*.toystore.com", "example.com"],
+                "auth": {
+                    "service": "authorino-cluster",
+                    "contextExtensions": {
+                        "host": "authconfig-A"
+                    }
+                },
+                "rules": []
+            }]
+        }"#;
+        let res = serde_json::from_str::<PluginConfiguration>(config);
+        if let Err(ref e) = res {
+            eprintln!("{e}");
+        }
+        assert!(res.is_ok());
+
+        let filter_config = res.unwrap();
+        assert_eq!(filter_config.policies.len(), 1);
+
+        let auth = filter_config.policies[0]
+            .auth
+            .as_ref()
+            .expect("auth should be set");
+        assert_eq!(auth.service, "authorino-cluster");
+        assert_eq!(
+            auth.context_extensions.get("host").map(String::as_str),
+            Some("authconfig-A")
+        );
+    }
+
     #[test]
     fn parse_config_invalid_data() {
         // data item fields are mutually exclusive
@@ -881,60 +1843,1075 @@ mod test {
 
         let result = FilterConfig::try_from(res.unwrap());
         let filter_config = result.expect("That didn't work");
-        let rlp_option = filter_config.index.get_longest_match_policy("example.com");
-        assert!(rlp_option.is_some());
+        let rlps = filter_config.index.get_policies("example.com", unscoped);
+        assert!(!rlps.is_empty());
 
-        let rlp_option = filter_config
+        let rlps = filter_config
             .index
-            .get_longest_match_policy("test.toystore.com");
-        assert!(rlp_option.is_some());
+            .get_policies("test.toystore.com", unscoped);
+        assert!(!rlps.is_empty());
 
-        let rlp_option = filter_config.index.get_longest_match_policy("unknown");
-        assert!(rlp_option.is_none());
+        let rlps = filter_config.index.get_policies("unknown", unscoped);
+        assert!(rlps.is_empty());
     }
 
     #[test]
-    fn path_tokenizes_with_escaping_basic() {
-        let path: Path = r"one\.two..three\\\\.four\\\.\five.".into();
-        assert_eq!(
-            path.tokens(),
-            vec!["one.two", "", r"three\\", r"four\.five", ""]
-        );
+    fn filter_config_keeps_policies_sharing_hostnames() {
+        let config = r#"{
+            "failureMode": "deny",
+            "rateLimitPolicies": [
+            {
+                "name": "gateway",
+                "domain": "gateway",
+                "service": "limitador-cluster",
+                "hostnames": ["*.toystore.com"],
+                "rules": []
+            },
+            {
+                "name": "route",
+                "domain": "route",
+                "service": "limitador-cluster",
+                "hostnames": ["*.toystore.com", "cars.toystore.com"],
+                "rules": []
+            }]
+        }"#;
+        let res = serde_json::from_str::<PluginConfiguration>(config);
+        assert!(res.is_ok());
+
+        let filter_config = FilterConfig::try_from(res.unwrap()).expect("That didn't work");
+        let rlps = filter_config
+            .index
+            .get_policies("cars.toystore.com", unscoped);
+        let names: Vec<&str> = rlps.iter().map(|rlp| rlp.name.as_str()).collect();
+        assert_eq!(names, vec!["route", "gateway"]);
     }
 
     #[test]
-    fn path_tokenizes_with_escaping_ends_with_separator() {
-        let path: Path = r"one.".into();
-        assert_eq!(path.tokens(), vec!["one", ""]);
+    fn filter_config_actions() {
+        let config = r#"{
+            "failureMode": "deny",
+            "rateLimitPolicies": [
+            {
+                "name": "default-order",
+                "domain": "rlp-ns-A/rlp-name-A",
+                "service": "limitador-cluster",
+                "hostnames": ["a.com"],
+                "auth": { "service": "authorino-cluster" },
+                "rules": []
+            },
+            {
+                "name": "rate-limit-only",
+                "domain": "rlp-ns-B/rlp-name-B",
+                "service": "limitador-cluster",
+                "hostnames": ["b.com"],
+                "rules": []
+            },
+            {
+                "name": "explicit-order",
+                "domain": "rlp-ns-C/rlp-name-C",
+                "service": "limitador-cluster",
+                "hostnames": ["c.com"],
+                "auth": { "service": "authorino-cluster" },
+                "actions": ["ratelimit", "auth"],
+                "rules": []
+            }]
+        }"#;
+        let res = serde_json::from_str::<PluginConfiguration>(config);
+        assert!(res.is_ok());
+
+        let filter_config = FilterConfig::try_from(res.unwrap()).expect("That didn't work");
+        let expected = [
+            ("a.com", vec![ActionKind::Auth, ActionKind::RateLimit]),
+            ("b.com", vec![ActionKind::RateLimit]),
+            ("c.com", vec![ActionKind::RateLimit, ActionKind::Auth]),
+        ];
+        for (hostname, actions) in expected {
+            let rlps = filter_config.index.get_policies(hostname, unscoped);
+            assert_eq!(rlps.len(), 1);
+            assert_eq!(rlps[0].actions(), actions.as_slice());
+        }
     }
 
     #[test]
-    fn path_tokenizes_with_escaping_ends_with_escape() {
-        let path: Path = r"one\".into();
-        assert_eq!(path.tokens(), vec!["one"]);
+    fn filter_config_invalid_actions() {
+        // auth action without auth section
+        let config = r#"{
+            "failureMode": "deny",
+            "rateLimitPolicies": [
+            {
+                "name": "rlp-ns-A/rlp-name-A",
+                "domain": "rlp-ns-A/rlp-name-A",
+                "service": "limitador-cluster",
+                "hostnames": ["a.com"],
+                "actions": ["auth", "ratelimit"],
+                "rules": []
+            }]
+        }"#;
+        let res = serde_json::from_str::<PluginConfiguration>(config);
+        assert!(res.is_ok());
+        assert!(FilterConfig::try_from(res.unwrap()).is_err());
+
+        // duplicated action
+        let config = r#"{
+            "failureMode": "deny",
+            "rateLimitPolicies": [
+            {
+                "name": "rlp-ns-A/rlp-name-A",
+                "domain": "rlp-ns-A/rlp-name-A",
+                "service": "limitador-cluster",
+                "hostnames": ["a.com"],
+                "actions": ["ratelimit", "ratelimit"],
+                "rules": []
+            }]
+        }"#;
+        let res = serde_json::from_str::<PluginConfiguration>(config);
+        assert!(res.is_ok());
+        assert!(FilterConfig::try_from(res.unwrap()).is_err());
     }
 
     #[test]
-    fn path_tokenizes_with_escaping_starts_with_separator() {
-        let path: Path = r".one".into();
-        assert_eq!(path.tokens(), vec!["", "one"]);
+    fn filter_config_routes() {
+        let config = r#"{
+            "failureMode": "deny",
+            "rateLimitPolicies": [
+            {
+                "name": "rlp-ns-A/rlp-name-A",
+                "domain": "rlp-ns-A/rlp-name-A",
+                "service": "limitador-cluster",
+                "hostnames": ["*.toystore.com"],
+                "routes": [
+                    { "pathPrefix": "/admin", "methods": ["POST", "PUT"] },
+                    { "routeName": "toystore-route" }
+                ],
+                "rules": []
+            }]
+        }"#;
+        let res = serde_json::from_str::<PluginConfiguration>(config);
+        if let Err(ref e) = res {
+            eprintln!("{e}");
+        }
+        assert!(res.is_ok());
+
+        let plugin_config = res.unwrap();
+        let routes = &plugin_config.policies[0].routes;
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes[0].path_prefix.as_deref(), Some("/admin"));
+        assert_eq!(routes[0].methods, vec!["POST", "PUT"]);
+        assert_eq!(routes[1].route_name.as_deref(), Some("toystore-route"));
+        assert!(FilterConfig::try_from(plugin_config).is_ok());
+
+        // path and path prefix are mutually exclusive
+        let config = r#"{
+            "failureMode": "deny",
+            "rateLimitPolicies": [
+            {
+                "name": "rlp-ns-A/rlp-name-A",
+                "domain": "rlp-ns-A/rlp-name-A",
+                "service": "limitador-cluster",
+                "hostnames": ["*.toystore.com"],
+                "routes": [ { "path": "/admin", "pathPrefix": "/admin" } ],
+                "rules": []
+            }]
+        }"#;
+        let res = serde_json::from_str::<PluginConfiguration>(config);
+        assert!(res.is_ok());
+        assert!(FilterConfig::try_from(res.unwrap()).is_err());
     }
 
     #[test]
-    fn path_tokenizes_with_escaping_starts_with_escape() {
-        let path: Path = r"\one".into();
-        assert_eq!(path.tokens(), vec!["one"]);
+    fn filter_config_deny_response() {
+        let config = r#"{
+            "failureMode": "deny",
+            "denyResponse": {
+                "status": 503,
+                "body": "limited ${user_id}${unknown}, retry later",
+                "headers": { "retry-after": "10" }
+            },
+            "rateLimitPolicies": [
+            {
+                "name": "rlp-ns-A/rlp-name-A",
+                "domain": "rlp-ns-A/rlp-name-A",
+                "service": "limitador-cluster",
+                "hostnames": ["*.toystore.com"],
+                "denyResponse": {
+                    "jsonBody": { "error": "too_many_requests", "user": "${user_id}", "limit": 10 }
+                },
+                "rules": []
+            }]
+        }"#;
+        let res = serde_json::from_str::<PluginConfiguration>(config);
+        if let Err(ref e) = res {
+            eprintln!("{e}");
+        }
+        assert!(res.is_ok());
+
+        let filter_config = FilterConfig::try_from(res.unwrap()).unwrap();
+        let values = HashMap::from([("user_id", "alice")]);
+
+        let deny_response = &filter_config.deny_response;
+        assert_eq!(deny_response.status, 503);
+        assert_eq!(deny_response.headers(), vec![("retry-after", "10")]);
+        assert_eq!(deny_response.body(&values), b"limited alice, retry later");
+
+        let policies = filter_config.index.get_policies("a.toystore.com", unscoped);
+        let deny_response = policies[0].deny_response.as_ref().unwrap();
+        assert_eq!(deny_response.status, 429);
+        assert_eq!(
+            deny_response.headers(),
+            vec![("content-type", "application/json")]
+        );
+        let body: serde_json::Value = serde_json::from_slice(&deny_response.body(&values)).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({ "error": "too_many_requests", "user": "alice", "limit": 10 })
+        );
     }
 
-    mod pattern_expressions {
-        use crate::configuration::{PatternExpression, WhenConditionOperator};
+    #[test]
+    fn filter_config_default_deny_response() {
+        let config = r#"{
+            "failureMode": "deny",
+            "rateLimitPolicies": []
+        }"#;
+        let res = serde_json::from_str::<PluginConfiguration>(config);
+        assert!(res.is_ok());
+
+        let filter_config = FilterConfig::try_from(res.unwrap()).unwrap();
+        let deny_response = &filter_config.deny_response;
+        assert_eq!(deny_response.status, 429);
+        assert!(deny_response.headers().is_empty());
+        assert_eq!(deny_response.body(&HashMap::new()), b"Too Many Requests\n");
+    }
+
+    #[test]
+    fn filter_config_invalid_deny_response() {
+        let config = r#"{
+            "failureMode": "deny",
+            "rateLimitPolicies": [
+            {
+                "name": "rlp-ns-A/rlp-name-A",
+                "domain": "rlp-ns-A/rlp-name-A",
+                "service": "limitador-cluster",
+                "hostnames": ["*.toystore.com"],
+                "denyResponse": { "status": 200 },
+                "rules": []
+            }]
+        }"#;
+        let res = serde_json::from_str::<PluginConfiguration>(config);
+        assert!(res.is_ok());
+        assert!(FilterConfig::try_from(res.unwrap()).is_err());
+
+        let config = r#"{
+            "failureMode": "deny",
+            "denyResponse": { "body": "limited", "jsonBody": {} },
+            "rateLimitPolicies": []
+        }"#;
+        let res = serde_json::from_str::<PluginConfiguration>(config);
+        assert!(res.is_ok());
+        assert!(FilterConfig::try_from(res.unwrap()).is_err());
+
+        for deny_response in [
+            r#"{ "contentType": "text/plain", "headers": { "Content-Type": "text/html" } }"#,
+            r#"{ "jsonBody": {}, "headers": { "content-type": "text/html" } }"#,
+        ] {
+            let config = format!(
+                r#"{{ "failureMode": "deny", "denyResponse": {deny_response}, "rateLimitPolicies": [] }}"#
+            );
+            let res = serde_json::from_str::<PluginConfiguration>(&config);
+            assert!(res.is_ok());
+            assert!(FilterConfig::try_from(res.unwrap()).is_err());
+        }
+    }
+
+    #[test]
+    fn parse_config_rate_limit_headers() {
+        let config = r#"{
+            "failureMode": "deny",
+            "rateLimitHeaders": "draft",
+            "rateLimitPolicies": []
+        }"#;
+        let res = serde_json::from_str::<PluginConfiguration>(config);
+        assert!(res.is_ok());
+        assert_eq!(res.unwrap().rate_limit_headers, RateLimitHeaders::Draft);
+
+        let config = r#"{
+            "failureMode": "deny",
+            "rateLimitPolicies": []
+        }"#;
+        let res = serde_json::from_str::<PluginConfiguration>(config);
+        assert!(res.is_ok());
+        assert_eq!(res.unwrap().rate_limit_headers, RateLimitHeaders::None);
+
+        let config = r#"{
+            "failureMode": "deny",
+            "rateLimitHeaders": "ietf",
+            "rateLimitPolicies": []
+        }"#;
+        assert!(serde_json::from_str::<PluginConfiguration>(config).is_err());
+    }
+
+    #[test]
+    fn filter_config_services() {
+        let config = r#"{
+            "failureMode": "deny",
+            "services": {
+                "limitador": {
+                    "kind": "ratelimit",
+                    "cluster": "limitador-cluster",
+                    "timeout": "200ms",
+                    "maxRetries": 2,
+                    "retryOn": ["unavailable", "deadline-exceeded"],
+                    "failureMode": "allow"
+                }
+            },
+            "rateLimitPolicies": [
+            {
+                "name": "rlp-ns-A/rlp-name-A",
+                "domain": "rlp-ns-A/rlp-name-A",
+                "service": "limitador",
+                "hostnames": ["*.toystore.com"],
+                "auth": { "service": "authorino-cluster" },
+                "rules": []
+            }]
+        }"#;
+        let res = serde_json::from_str::<PluginConfiguration>(config);
+        if let Err(ref e) = res {
+            eprintln!("{e}");
+        }
+        assert!(res.is_ok());
+
+        let filter_config = FilterConfig::try_from(res.unwrap()).unwrap();
+        let limitador = filter_config.service("limitador");
+        assert_eq!(limitador.kind, ServiceKind::RateLimit);
+        assert_eq!(limitador.cluster, "limitador-cluster");
+        assert_eq!(limitador.timeout, Duration::from_millis(200));
+        assert_eq!(
+            limitador.failure_mode,
+            FailureModes::all(FailureMode::Allow)
+        );
+        assert!(limitador.should_retry(RetryOn::DeadlineExceeded as u32, 1));
+        assert!(!limitador.should_retry(RetryOn::DeadlineExceeded as u32, 2));
+        assert!(!limitador.should_retry(RetryOn::Internal as u32, 0));
+
+        // clusters referenced directly by the policies
+        let authorino = filter_config.service("authorino-cluster");
+        assert_eq!(authorino.kind, ServiceKind::Auth);
+        assert_eq!(authorino.cluster, "authorino-cluster");
+        assert_eq!(authorino.timeout, Duration::from_secs(5));
+        assert_eq!(authorino.max_retries, 0);
+        assert_eq!(authorino.failure_mode, FailureModes::default());
+    }
+
+    #[test]
+    fn filter_config_invalid_services() {
+        // ratelimit policy referencing an auth service
+        let config = r#"{
+            "failureMode": "deny",
+            "services": {
+                "authorino": { "kind": "auth", "cluster": "authorino-cluster" }
+            },
+            "rateLimitPolicies": [
+            {
+                "name": "rlp-ns-A/rlp-name-A",
+                "domain": "rlp-ns-A/rlp-name-A",
+                "service": "authorino",
+                "hostnames": ["*.toystore.com"],
+                "rules": []
+            }]
+        }"#;
+        let res = serde_json::from_str::<PluginConfiguration>(config);
+        assert!(res.is_ok());
+        assert!(FilterConfig::try_from(res.unwrap()).is_err());
+
+        let config = r#"{
+            "failureMode": "deny",
+            "services": {
+                "limitador": { "kind": "ratelimit", "cluster": "limitador-cluster", "timeout": "0s" }
+            },
+            "rateLimitPolicies": []
+        }"#;
+        let res = serde_json::from_str::<PluginConfiguration>(config);
+        assert!(res.is_ok());
+        assert!(FilterConfig::try_from(res.unwrap()).is_err());
+
+        let config = r#"{
+            "failureMode": "deny",
+            "services": {
+                "limitador": { "kind": "ratelimit", "cluster": "limitador-cluster", "timeout": "1h" }
+            },
+            "rateLimitPolicies": []
+        }"#;
+        assert!(serde_json::from_str::<PluginConfiguration>(config).is_err());
+    }
+
+    #[test]
+    fn filter_config_failure_modes() {
+        let config = r#"{
+            "failureMode": { "timeout": "allow", "default": "deny" },
+            "services": {
+                "limitador": {
+                    "kind": "ratelimit",
+                    "cluster": "limitador-cluster",
+                    "failureMode": { "unknown": "allow" }
+                }
+            },
+            "rateLimitPolicies": [
+            {
+                "name": "rlp-ns-A/rlp-name-A",
+                "domain": "rlp-ns-A/rlp-name-A",
+                "service": "limitador",
+                "hostnames": ["*.toystore.com"],
+                "failureMode": "allow",
+                "rules": []
+            }]
+        }"#;
+        let res = serde_json::from_str::<PluginConfiguration>(config);
+        if let Err(ref e) = res {
+            eprintln!("{e}");
+        }
+        assert!(res.is_ok());
+
+        let filter_config = FilterConfig::try_from(res.unwrap()).unwrap();
+        let failure_mode = &filter_config.failure_mode;
+        assert_eq!(failure_mode.get(Failure::Timeout), Some(FailureMode::Allow));
+        assert_eq!(
+            failure_mode.get(Failure::DispatchError),
+            Some(FailureMode::Deny)
+        );
+        assert_eq!(
+            failure_mode.get(Failure::MalformedResponse),
+            Some(FailureMode::Deny)
+        );
+
+        let failure_mode = &filter_config.service("limitador").failure_mode;
+        assert_eq!(failure_mode.get(Failure::Unknown), Some(FailureMode::Allow));
+        assert_eq!(failure_mode.get(Failure::Timeout), None);
+
+        let policies = filter_config.index.get_policies("a.toystore.com", unscoped);
+        assert_eq!(
+            policies[0].failure_mode,
+            FailureModes::all(FailureMode::Allow)
+        );
+
+        let config = r#"{
+            "failureMode": { "timeout": "allow", "retry": "deny" },
+            "rateLimitPolicies": []
+        }"#;
+        assert!(serde_json::from_str::<PluginConfiguration>(config).is_err());
+    }
+
+    #[test]
+    fn filter_config_invalid_regex() {
+        let config = r#"{
+            "failureMode": "deny",
+            "rateLimitPolicies": [
+            {
+                "name": "rlp-ns-A/rlp-name-A",
+                "domain": "rlp-ns-A/rlp-name-A",
+                "service": "limitador-cluster",
+                "hostnames": ["*.toystore.com"],
+                "rules": [
+                {
+                    "data": [ { "static": { "key": "all", "value": "1" } } ]
+                },
+                {
+                    "conditions": [
+                    {
+                        "allOf": [
+                        {
+                            "selector": "request.host",
+                            "operator": "matches",
+                            "value": "*.com"
+                        }]
+                    }],
+                    "data": [ { "static": { "key": "host", "value": "1" } } ]
+                }]
+            }]
+        }"#;
+        let res = serde_json::from_str::<PluginConfiguration>(config);
+        assert!(res.is_ok());
+        let err = FilterConfig::try_from(res.unwrap()).err().unwrap();
+        assert!(
+            err.starts_with(
+                "policy `rlp-ns-A/rlp-name-A`, rule #1: invalid regular expression `*.com`"
+            ),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn normalizations() {
+        assert_eq!(
+            Normalization::apply_all(
+                &[Normalization::Trim, Normalization::PercentDecode],
+                " /a%20b%2Fc%zz%4 ".to_string()
+            ),
+            "/a b/c%zz%4"
+        );
+        assert_eq!(
+            Normalization::apply_all(&[Normalization::Lowercase], "ToyStore".to_string()),
+            "toystore"
+        );
+
+        let selector: SelectorItem =
+            serde_json::from_str(r#"{"selector": "request.host", "normalize": ["lowercase"]}"#)
+                .unwrap();
+        assert!(selector.compile().is_ok());
+        let selector: SelectorItem = serde_json::from_str(
+            r#"{"selector": "request.host", "normalize": ["lowercase"], "format": "hex"}"#,
+        )
+        .unwrap();
+        assert!(selector.compile().is_err());
+        let selector: SelectorItem =
+            serde_json::from_str(r#"{"selector": "request.time", "normalize": ["trim"]}"#).unwrap();
+        assert!(selector.compile().is_err());
+    }
+
+    #[test]
+    fn filter_config_hostnames_with_ports() {
+        let config = |hostname: &str| {
+            format!(
+                r#"{{
+                "failureMode": "deny",
+                "rateLimitPolicies": [
+                {{
+                    "name": "rlp-ns-A/rlp-name-A",
+                    "domain": "rlp-ns-A/rlp-name-A",
+                    "service": "limitador-cluster",
+                    "hostnames": ["{hostname}"],
+                    "rules": [ {{ "data": [ {{ "static": {{ "key": "a", "value": "1" }} }} ] }} ]
+                }}]
+            }}"#
+            )
+        };
+        let res = serde_json::from_str::<PluginConfiguration>(&config("api.example.com:8443"));
+        let filter_config = FilterConfig::try_from(res.unwrap()).unwrap();
+        assert!(filter_config.index.has_ports());
+
+        let res = serde_json::from_str::<PluginConfiguration>(&config("api.example.com:https"));
+        assert!(FilterConfig::try_from(res.unwrap()).is_err());
+    }
+
+    #[test]
+    fn filter_config_hostname_conflicts() {
+        let config = |strategy: &str, hostnames_b: &str| {
+            format!(
+                r#"{{
+                "failureMode": "deny",
+                "hostnameConflicts": "{strategy}",
+                "rateLimitPolicies": [
+                {{
+                    "name": "rlp-a",
+                    "domain": "a",
+                    "service": "limitador-cluster",
+                    "hostnames": ["*.toystore.com"],
+                    "rules": [ {{ "data": [ {{ "static": {{ "key": "a", "value": "1" }} }} ] }} ]
+                }},
+                {{
+                    "name": "rlp-b",
+                    "domain": "b",
+                    "service": "limitador-cluster",
+                    "hostnames": {hostnames_b},
+                    "rules": [ {{ "data": [ {{ "static": {{ "key": "b", "value": "1" }} }} ] }} ]
+                }}]
+            }}"#
+            )
+        };
+        let filter_config = |strategy: &str, hostnames_b: &str| {
+            let res = serde_json::from_str::<PluginConfiguration>(&config(strategy, hostnames_b));
+            FilterConfig::try_from(res.unwrap())
+        };
+        let names = |filter_config: &FilterConfig| -> Vec<String> {
+            filter_config
+                .index
+                .get_policies("cars.toystore.com", unscoped)
+                .iter()
+                .map(|rlp| rlp.name.clone())
+                .collect()
+        };
+
+        let merged = filter_config("merge", r#"["*.TOYSTORE.com."]"#).unwrap();
+        assert_eq!(names(&merged), vec!["rlp-a", "rlp-b"]);
+
+        let first_wins = filter_config("firstWins", r#"["*.toystore.com"]"#).unwrap();
+        assert_eq!(names(&first_wins), vec!["rlp-a"]);
+
+        assert_eq!(
+            filter_config("reject", r#"["*.toystore.com"]"#).err(),
+            Some(
+                "hostname `*.toystore.com` claimed by both policies `rlp-a` and `rlp-b`"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            filter_config("reject", r#"["toystore.com", "toystore.com"]"#).err(),
+            Some("policy `rlp-b`: hostname `toystore.com` listed more than once".to_string())
+        );
+        // distinct claims
+        assert!(filter_config("reject", r#"["*.toystore.com:8443", "cars.toystore.com"]"#).is_ok());
+    }
+
+    #[test]
+    fn filter_config_hostname_wildcards() {
+        let config = |depth: &str| {
+            format!(
+                r#"{{
+                "failureMode": "deny",
+                {depth}
+                "rateLimitPolicies": [
+                {{
+                    "name": "rlp-a",
+                    "domain": "a",
+                    "service": "limitador-cluster",
+                    "hostnames": ["*.toystore.com"],
+                    "rules": [ {{ "data": [ {{ "static": {{ "key": "a", "value": "1" }} }} ] }} ]
+                }}]
+            }}"#
+            )
+        };
+        let matches = |depth: &str, subdomain: &str| {
+            let res = serde_json::from_str::<PluginConfiguration>(&config(depth)).unwrap();
+            !FilterConfig::try_from(res)
+                .unwrap()
+                .index
+                .get_policies(subdomain, unscoped)
+                .is_empty()
+        };
+
+        assert!(matches("", "eu.cars.toystore.com"));
+        assert!(matches(
+            r#""hostnameWildcards": "anyDepth","#,
+            "eu.cars.toystore.com"
+        ));
+        assert!(matches(
+            r#""hostnameWildcards": "singleLabel","#,
+            "cars.toystore.com"
+        ));
+        assert!(!matches(
+            r#""hostnameWildcards": "singleLabel","#,
+            "eu.cars.toystore.com"
+        ));
+        assert!(serde_json::from_str::<PluginConfiguration>(&config(
+            r#""hostnameWildcards": "anyLabel","#
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn filter_config_predicates() {
+        let config = r#"{
+            "failureMode": "deny",
+            "rateLimitPolicies": [
+            {
+                "name": "rlp-ns-A/rlp-name-A",
+                "domain": "rlp-ns-A/rlp-name-A",
+                "service": "limitador-cluster",
+                "hostnames": ["*.toystore.com"],
+                "rules": [
+                {
+                    "conditions": [
+                    {
+                        "predicate": "request.method in ['POST', 'PUT'] && request.headers['x-tier'] != 'gold'"
+                    },
+                    {
+                        "allOf": [
+                        {
+                            "selector": "request.url_path",
+                            "operator": "startswith",
+                            "value": "/admin"
+                        }],
+                        "predicate": "auth.identity.admin == true"
+                    }],
+                    "data": [ { "static": { "key": "admin", "value": "1" } } ]
+                }]
+            }]
+        }"#;
+        let res = serde_json::from_str::<PluginConfiguration>(config);
+        if let Err(ref e) = res {
+            eprintln!("{e}");
+        }
+        assert!(res.is_ok());
+
+        let plugin_config = res.unwrap();
+        let conditions = &plugin_config.policies[0].rules[0].conditions;
+        assert!(conditions[0].all_of.is_empty());
+        assert_eq!(
+            conditions[1].predicate.as_ref().unwrap().source,
+            "auth.identity.admin == true"
+        );
+        assert!(FilterConfig::try_from(plugin_config).is_ok());
+
+        let config = r#"{
+            "failureMode": "deny",
+            "rateLimitPolicies": [
+            {
+                "name": "rlp-ns-A/rlp-name-A",
+                "domain": "rlp-ns-A/rlp-name-A",
+                "service": "limitador-cluster",
+                "hostnames": ["*.toystore.com"],
+                "rules": [
+                {
+                    "conditions": [ { "predicate": "request.method in [" } ],
+                    "data": [ { "static": { "key": "admin", "value": "1" } } ]
+                }]
+            }]
+        }"#;
+        let res = serde_json::from_str::<PluginConfiguration>(config);
+        assert!(res.is_ok());
+        assert!(FilterConfig::try_from(res.unwrap()).is_err());
+    }
+
+    #[test]
+    fn parse_durations() {
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_duration("3s"), Ok(Duration::from_secs(3)));
+        assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+        assert!(parse_duration("ms").is_err());
+        assert!(parse_duration("10").is_err());
+        assert!(parse_duration("1.5s").is_err());
+    }
+
+    #[test]
+    fn path_tokenizes_with_escaping_basic() {
+        let path: Path = r"one\.two..three\\\\.four\\\.\five.".into();
+        assert_eq!(
+            path.tokens(),
+            vec!["one.two", "", r"three\\", r"four\.five", ""]
+        );
+    }
+
+    #[test]
+    fn path_tokenizes_with_escaping_ends_with_separator() {
+        let path: Path = r"one.".into();
+        assert_eq!(path.tokens(), vec!["one", ""]);
+    }
+
+    #[test]
+    fn path_tokenizes_with_escaping_ends_with_escape() {
+        let path: Path = r"one\".into();
+        assert_eq!(path.tokens(), vec!["one"]);
+    }
+
+    #[test]
+    fn path_tokenizes_with_escaping_starts_with_separator() {
+        let path: Path = r".one".into();
+        assert_eq!(path.tokens(), vec!["", "one"]);
+    }
+
+    #[test]
+    fn path_tokenizes_with_escaping_starts_with_escape() {
+        let path: Path = r"\one".into();
+        assert_eq!(path.tokens(), vec!["one"]);
+    }
+
+    mod pattern_expressions {
+        use crate::attribute::serialize_strings;
+        use crate::configuration::{Atom, Expression, PatternExpression, WhenConditionOperator};
+
+        fn pattern(
+            selector: &str,
+            operator: WhenConditionOperator,
+            value: &str,
+        ) -> PatternExpression {
+            let p = PatternExpression {
+                selector: selector.to_string(),
+                operator,
+                value: Some(value.to_string()),
+                ignore_case: false,
+                normalize: Vec::new(),
+                path: Default::default(),
+                compiled: Default::default(),
+            };
+            p.compile().expect("Should compile fine!");
+            p
+        }
+
+        #[test]
+        fn test_map() {
+            let labels = serialize_strings(&["app", "toystore", "version", "v1"], 2);
+            let p = pattern(
+                "source.labels",
+                WhenConditionOperator::Equal,
+                "{'app': 'toystore', 'version': 'v1'}",
+            );
+            assert_eq!(p.eval(labels.clone()), Ok(true));
+            let p = pattern(
+                "source.labels",
+                WhenConditionOperator::NotEqual,
+                "{'app': 'toystore'}",
+            );
+            assert_eq!(p.eval(labels.clone()), Ok(true));
+            let p = pattern("source.labels", WhenConditionOperator::Contains, "app");
+            assert_eq!(p.eval(labels.clone()), Ok(true));
+            let p = pattern("source.labels", WhenConditionOperator::Contains, "x-tier");
+            assert_eq!(p.eval(labels), Ok(false));
+            let p = pattern(
+                "request.headers",
+                WhenConditionOperator::Contains,
+                "'x-tier'",
+            );
+            assert_eq!(p.eval(serialize_strings(&["x-tier", "gold"], 1)), Ok(true));
+            assert_eq!(p.eval(Vec::new()), Ok(false));
+        }
+
+        #[test]
+        fn test_list() {
+            let items = serialize_strings(&["admin", "dev"], 2);
+            let p = pattern("foobar", WhenConditionOperator::Equal, "['admin', 'dev']");
+            assert_eq!(p.eval(items.clone()), Ok(true));
+            let p = pattern("foobar", WhenConditionOperator::NotEqual, "['admin']");
+            assert_eq!(p.eval(items), Ok(true));
+        }
+
+        #[test]
+        fn test_ordering() {
+            let size = |size: i64| size.to_le_bytes().to_vec();
+            let p = pattern("request.size", WhenConditionOperator::GreaterThan, "1MiB");
+            assert_eq!(p.eval(size(2 << 20)), Ok(true));
+            assert_eq!(p.eval(size(1 << 20)), Ok(false));
+            let p = pattern(
+                "request.size",
+                WhenConditionOperator::GreaterOrEqual,
+                "1MiB",
+            );
+            assert_eq!(p.eval(size(1 << 20)), Ok(true));
+            let p = pattern("destination.port", WhenConditionOperator::LessThan, "1024");
+            assert_eq!(p.eval(size(443)), Ok(true));
+            let p = pattern("foobar", WhenConditionOperator::LessOrEqual, "1.5");
+            assert_eq!(p.eval(1.5_f64.to_le_bytes().to_vec()), Ok(true));
+
+            let nanos = 1_700_000_000_000_000_000_i64.to_le_bytes().to_vec();
+            let p = pattern(
+                "request.time",
+                WhenConditionOperator::GreaterThan,
+                "2023-01-01T00:00:00Z",
+            );
+            assert_eq!(p.eval(nanos), Ok(true));
+        }
+
+        #[test]
+        fn test_membership() {
+            let p = pattern("request.method", WhenConditionOperator::In, "[GET, HEAD]");
+            assert_eq!(p.eval(b"HEAD".to_vec()), Ok(true));
+            assert_eq!(p.eval(b"POST".to_vec()), Ok(false));
+            let p = pattern(
+                "request.method",
+                WhenConditionOperator::NotIn,
+                "['GET', 'HEAD']",
+            );
+            assert_eq!(p.eval(b"POST".to_vec()), Ok(true));
+            let p = pattern("destination.port", WhenConditionOperator::In, "[80, 443]");
+            assert_eq!(p.eval(443_i64.to_le_bytes().to_vec()), Ok(true));
+            let p = pattern("request.path", WhenConditionOperator::Contains, "/admin/");
+            assert_eq!(p.eval(b"/api/admin/users".to_vec()), Ok(true));
+        }
+
+        #[test]
+        fn test_matches() {
+            let p = pattern(
+                "request.host",
+                WhenConditionOperator::Matches,
+                r"^(cars|toys)\.example\.com$",
+            );
+            assert!(p.compiled.get().unwrap().matcher.is_some());
+            assert_eq!(p.eval(b"toys.example.com".to_vec()), Ok(true));
+            assert_eq!(p.eval(b"boats.example.com".to_vec()), Ok(false));
+        }
+
+        #[test]
+        fn test_glob() {
+            let p = pattern(
+                "request.url_path",
+                WhenConditionOperator::Glob,
+                "/api/*/orders/*",
+            );
+            assert_eq!(p.eval(b"/api/v1/orders/42".to_vec()), Ok(true));
+            assert_eq!(p.eval(b"/api/v1/orders".to_vec()), Ok(false));
+            let p = pattern(
+                "request.url_path",
+                WhenConditionOperator::Glob,
+                "/api/*/orders/**",
+            );
+            assert_eq!(p.eval(b"/api/v1/orders/42/items".to_vec()), Ok(true));
+            assert_eq!(p.eval(b"/api/a/b/c/orders".to_vec()), Ok(false));
+            assert_eq!(p.eval(b"/api/a/b/orders/42".to_vec()), Ok(false));
+            let p = pattern("request.host", WhenConditionOperator::NotGlob, "*.internal");
+            assert_eq!(p.eval(b"api.internal".to_vec()), Ok(false));
+            assert_eq!(p.eval(b"api.example.com".to_vec()), Ok(true));
+            let p = pattern("foobar", WhenConditionOperator::Glob, "'v?.+'");
+            assert_eq!(p.eval(b"v1.2".to_vec()), Ok(true));
+
+            let p = PatternExpression {
+                selector: "destination.port".to_string(),
+                operator: WhenConditionOperator::Glob,
+                value: Some("80*".to_string()),
+                ignore_case: false,
+                normalize: Vec::new(),
+                path: Default::default(),
+                compiled: Default::default(),
+            };
+            assert!(p.compile().is_err());
+        }
+
+        #[test]
+        fn test_cidr() {
+            let p = pattern(
+                "source.address",
+                WhenConditionOperator::InCidr,
+                "[10.0.0.0/8, '192.168.0.0/16', fd00::/8]",
+            );
+            assert_eq!(p.eval(b"10.1.2.3:5432".to_vec()), Ok(true));
+            assert_eq!(p.eval(b"[fd00::1]:5432".to_vec()), Ok(true));
+            assert_eq!(p.eval(b"203.0.113.7".to_vec()), Ok(false));
+            assert!(p.eval(b"unix:/var/run/socket".to_vec()).is_err());
+            // only the matcher checks the address
+            assert!(matches!(
+                p.compiled.get().unwrap().expression,
+                Expression::Atom(Atom::Bool(_))
+            ));
+            let p = pattern(
+                "source.address",
+                WhenConditionOperator::NotInCidr,
+                "10.0.0.0/8",
+            );
+            assert_eq!(p.eval(b"203.0.113.7:80".to_vec()), Ok(true));
+
+            for (selector, value) in [
+                ("source.address", "10.0.0.0/33"),
+                ("source.port", "10.0.0.0/8"),
+            ] {
+                let p = PatternExpression {
+                    selector: selector.to_string(),
+                    operator: WhenConditionOperator::InCidr,
+                    value: Some(value.to_string()),
+                    ignore_case: false,
+                    normalize: Vec::new(),
+                    path: Default::default(),
+                    compiled: Default::default(),
+                };
+                assert!(p.compile().is_err(), "{selector} {value}");
+            }
+        }
+
+        fn parse(json: &str) -> PatternExpression {
+            let p: PatternExpression = serde_json::from_str(json).expect("valid pattern");
+            p.compile().expect("Should compile fine!");
+            p
+        }
+
+        #[test]
+        fn test_ignore_case() {
+            let p = parse(
+                r#"{"selector": "request.headers.x-tier", "operator": "eq", "value": "Gold", "ignoreCase": true}"#,
+            );
+            assert_eq!(p.eval(b"GOLD".to_vec()), Ok(true));
+            let p = parse(
+                r#"{"selector": "request.method", "operator": "in", "value": "[get, head]", "ignoreCase": true}"#,
+            );
+            assert_eq!(p.eval(b"GET".to_vec()), Ok(true));
+            let p = parse(
+                r#"{"selector": "request.host", "operator": "matches", "value": "^API\\.", "ignoreCase": true}"#,
+            );
+            assert_eq!(p.eval(b"api.example.com".to_vec()), Ok(true));
+            let p = parse(
+                r#"{"selector": "request.host", "operator": "glob", "value": "*.Example.com", "ignoreCase": true}"#,
+            );
+            assert_eq!(p.eval(b"API.EXAMPLE.COM".to_vec()), Ok(true));
+            let p = parse(r#"{"selector": "request.method", "operator": "eq", "value": "get"}"#);
+            assert_eq!(p.eval(b"GET".to_vec()), Ok(false));
+        }
+
+        #[test]
+        fn test_normalize() {
+            let p = parse(
+                r#"{"selector": "request.url_path", "operator": "eq", "value": "/café/menu", "normalize": ["percentDecode", "lowercase"]}"#,
+            );
+            assert_eq!(p.eval(b"/CAF%C3%A9/Menu".to_vec()), Ok(true));
+            let p = parse(
+                r#"{"selector": "request.headers.x-tier", "operator": "eq", "value": "gold", "normalize": ["trim"]}"#,
+            );
+            assert_eq!(p.eval(b"  gold ".to_vec()), Ok(true));
+
+            let p: PatternExpression = serde_json::from_str(
+                r#"{"selector": "request.size", "operator": "gt", "value": "1", "normalize": ["trim"]}"#,
+            )
+            .unwrap();
+            assert!(p.compile().is_err());
+        }
+
+        #[test]
+        fn test_presence() {
+            let p = pattern("request.headers", WhenConditionOperator::Exists, "");
+            assert_eq!(p.applies(Some(Vec::new())), Ok(true));
+            assert_eq!(p.applies(None), Ok(false));
+            let p = pattern("auth.identity.org", WhenConditionOperator::NotExists, "");
+            assert_eq!(p.applies(Some(b"acme".to_vec())), Ok(false));
+            assert_eq!(p.applies(None), Ok(true));
+            let p = pattern("request.method", WhenConditionOperator::Equal, "GET");
+            assert_eq!(p.applies(None), Ok(false));
+        }
+
+        #[test]
+        fn test_value_only_optional_for_presence() {
+            let p = parse(r#"{ "selector": "auth.identity.org", "operator": "notexists" }"#);
+            assert_eq!(p.applies(None), Ok(true));
+            for json in [
+                r#"{ "selector": "request.method", "operator": "eq" }"#,
+                r#"{ "selector": "request.size", "operator": "gt" }"#,
+                r#"{ "selector": "source.address", "operator": "incidr" }"#,
+            ] {
+                let p: PatternExpression = serde_json::from_str(json).expect("valid pattern");
+                assert!(p.compile().is_err(), "{json}");
+            }
+            let p = parse(r#"{ "selector": "request.method", "operator": "eq", "value": "" }"#);
+            assert_eq!(p.applies(Some(Vec::new())), Ok(true));
+        }
+
+        #[test]
+        fn test_operators_are_type_checked() {
+            for (selector, operator, value) in [
+                ("request.method", WhenConditionOperator::GreaterThan, "GET"),
+                ("request.method", WhenConditionOperator::In, "GET"),
+                ("request.size", WhenConditionOperator::GreaterThan, "big"),
+                ("request.size", WhenConditionOperator::In, "[1, two]"),
+                ("request.size", WhenConditionOperator::StartsWith, "1"),
+                ("connection.mtls", WhenConditionOperator::LessThan, "true"),
+            ] {
+                let p = PatternExpression {
+                    selector: selector.to_string(),
+                    operator,
+                    value: Some(value.to_string()),
+                    ignore_case: false,
+                    normalize: Vec::new(),
+                    path: Default::default(),
+                    compiled: Default::default(),
+                };
+                assert!(p.compile().is_err(), "{selector} {value}");
+            }
+        }
+
+        #[test]
+        fn test_unsupported_map_operators() {
+            let p = PatternExpression {
+                selector: "source.labels".to_string(),
+                operator: WhenConditionOperator::StartsWith,
+                value: Some("app".to_string()),
+                ignore_case: false,
+                normalize: Vec::new(),
+                path: Default::default(),
+                compiled: Default::default(),
+            };
+            assert!(p.compile().is_err());
+        }
 
         #[test]
         fn test_legacy_string() {
             let p = PatternExpression {
                 selector: "request.id".to_string(),
                 operator: WhenConditionOperator::Equal,
-                value: "request_id".to_string(),
+                value: Some("request_id".to_string()),
+                ignore_case: false,
+                normalize: Vec::new(),
                 path: Default::default(),
                 compiled: Default::default(),
             };
@@ -952,7 +2929,9 @@ mod test {
             let p = PatternExpression {
                 selector: "request.id".to_string(),
                 operator: WhenConditionOperator::Equal,
-                value: "\"request_id\"".to_string(),
+                value: Some("\"request_id\"".to_string()),
+                ignore_case: false,
+                normalize: Vec::new(),
                 path: Default::default(),
                 compiled: Default::default(),
             };
@@ -970,7 +2949,9 @@ mod test {
             let p = PatternExpression {
                 selector: "request.id".to_string(),
                 operator: WhenConditionOperator::Equal,
-                value: "123".to_string(),
+                value: Some("123".to_string()),
+                ignore_case: false,
+                normalize: Vec::new(),
                 path: Default::default(),
                 compiled: Default::default(),
             };
@@ -988,7 +2969,9 @@ mod test {
             let p = PatternExpression {
                 selector: "foobar".to_string(),
                 operator: WhenConditionOperator::Equal,
-                value: "\"123\"".to_string(),
+                value: Some("\"123\"".to_string()),
+                ignore_case: false,
+                normalize: Vec::new(),
                 path: Default::default(),
                 compiled: Default::default(),
             };
@@ -1006,7 +2989,9 @@ mod test {
             let p = PatternExpression {
                 selector: "destination.port".to_string(),
                 operator: WhenConditionOperator::Equal,
-                value: "8080".to_string(),
+                value: Some("8080".to_string()),
+                ignore_case: false,
+                normalize: Vec::new(),
                 path: Default::default(),
                 compiled: Default::default(),
             };
@@ -1024,7 +3009,9 @@ mod test {
             let p = PatternExpression {
                 selector: "foobar".to_string(),
                 operator: WhenConditionOperator::Equal,
-                value: "8080".to_string(),
+                value: Some("8080".to_string()),
+                ignore_case: false,
+                normalize: Vec::new(),
                 path: Default::default(),
                 compiled: Default::default(),
             };
@@ -1042,7 +3029,9 @@ mod test {
             let p = PatternExpression {
                 selector: "foobar".to_string(),
                 operator: WhenConditionOperator::Equal,
-                value: "1.0".to_string(),
+                value: Some("1.0".to_string()),
+                ignore_case: false,
+                normalize: Vec::new(),
                 path: Default::default(),
                 compiled: Default::default(),
             };
@@ -1060,7 +3049,9 @@ mod test {
             let p = PatternExpression {
                 selector: "connection.mtls".to_string(),
                 operator: WhenConditionOperator::Equal,
-                value: "true".to_string(),
+                value: Some("true".to_string()),
+                ignore_case: false,
+                normalize: Vec::new(),
                 path: Default::default(),
                 compiled: Default::default(),
             };
@@ -1078,7 +3069,9 @@ mod test {
             let p = PatternExpression {
                 selector: "request.time".to_string(),
                 operator: WhenConditionOperator::Equal,
-                value: "2023-05-28T00:00:00+00:00".to_string(),
+                value: Some("2023-05-28T00:00:00+00:00".to_string()),
+                ignore_case: false,
+                normalize: Vec::new(),
                 path: Default::default(),
                 compiled: Default::default(),
             };
@@ -31,9 +31,18 @@ mod token_bucket;
 mod value;
 
 pub use {
+    address::{Address, SocketAddress},
+    attribute_context::{
+        AttributeContext, AttributeContext_HttpRequest, AttributeContext_Peer,
+        AttributeContext_Request,
+    },
+    external_auth::{CheckRequest, CheckResponse, DeniedHttpResponse},
     ratelimit::{RateLimitDescriptor, RateLimitDescriptor_Entry},
-    rls::{RateLimitRequest, RateLimitResponse, RateLimitResponse_Code},
+    rls::{
+        RateLimitRequest, RateLimitResponse, RateLimitResponse_Code,
+        RateLimitResponse_DescriptorStatus, RateLimitResponse_RateLimit_Unit,
+    },
 };
 
 #[cfg(test)]
-pub use base::HeaderValue;
+pub use {base::HeaderValue, rls::RateLimitResponse_RateLimit};
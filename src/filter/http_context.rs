@@ -1,16 +1,27 @@
-use crate::configuration::{FailureMode, FilterConfig};
-use crate::envoy::{RateLimitRequest, RateLimitResponse, RateLimitResponse_Code};
+use crate::attribute::{get_attribute, json_attribute_bytes, lookup_json_attribute};
+use crate::authority::Authority;
+use crate::configuration::{Failure, FailureMode, FilterConfig, RateLimitHeaders};
+use crate::envoy::{
+    CheckResponse, RateLimitDescriptor, RateLimitRequest, RateLimitResponse,
+    RateLimitResponse_Code, RateLimitResponse_DescriptorStatus,
+};
 use crate::filter::http_context::TracingHeader::{Baggage, Traceparent, Tracestate};
-use crate::policy::Policy;
+use crate::policy::{ActionKind, AuthAction, Policy, RequestRoute};
+use crate::service::auth::{AuthService, AUTH_METHOD_NAME, AUTH_SERVICE_NAME};
+use crate::service::rate_limit::{
+    most_restrictive_quota, RateLimitQuota, RATELIMIT_METHOD_NAME, RATELIMIT_SERVICE_NAME,
+};
+use crate::service::{
+    grpc_message, GRPC_STATUS_DEADLINE_EXCEEDED, GRPC_STATUS_INTERNAL,
+    GRPC_STATUS_PERMISSION_DENIED, GRPC_STATUS_RESOURCE_EXHAUSTED, GRPC_STATUS_UNAVAILABLE,
+};
 use log::{debug, warn};
-use protobuf::Message;
+use protobuf::{Message, RepeatedField};
 use proxy_wasm::traits::{Context, HttpContext};
-use proxy_wasm::types::{Action, Bytes};
+use proxy_wasm::types::{Action, Bytes, Status};
+use std::cell::OnceCell;
+use std::collections::{HashMap, VecDeque};
 use std::rc::Rc;
-use std::time::Duration;
-
-const RATELIMIT_SERVICE_NAME: &str = "envoy.service.ratelimit.v3.RateLimitService";
-const RATELIMIT_METHOD_NAME: &str = "ShouldRateLimit";
 
 // tracing headers
 pub enum TracingHeader {
@@ -33,149 +44,456 @@ impl TracingHeader {
     }
 }
 
+// Outcome of processing a single action of the policies
+enum ActionOutcome {
+    // gRPC calls dispatched, the request is paused until their responses
+    Dispatched,
+    // Nothing to do for this request, move on to the next action
+    Skipped,
+    // A local response has been sent, the request is done
+    Responded,
+}
+
+// What is needed to handle the response of an in-flight gRPC call
+pub enum GrpcAction {
+    Auth,
+    RateLimit {
+        domain: String,
+        descriptors: Vec<RateLimitDescriptor>,
+    },
+}
+
+// An in-flight gRPC call, kept to handle its response or to retry it
+pub struct GrpcCall {
+    pub action: GrpcAction,
+    // Name of the service called
+    pub service: String,
+    pub request: Bytes,
+    pub retries: u32,
+}
+
 pub struct Filter {
     pub context_id: u32,
     pub config: Rc<FilterConfig>,
     pub response_headers_to_add: Vec<(String, String)>,
     pub tracing_headers: Vec<(TracingHeader, Bytes)>,
+    pub authority: Authority,
+    // Fetched when needed to select the policies
+    pub route: OnceCell<RequestRoute>,
+    // Policies selected for the request, the most specific first
+    pub policies: Vec<Rc<Policy>>,
+    // Actions of the selected policy still to be processed, in order
+    pub pending_actions: VecDeque<ActionKind>,
+    // In-flight gRPC calls, by call id
+    pub grpc_calls: HashMap<u32, GrpcCall>,
+    // Attributes produced by previous actions, looked up ahead of Envoy's properties
+    pub attributes: serde_json::Map<String, serde_json::Value>,
+    // Most restrictive quota reported by the rate limit responses, when rate limit headers are enabled
+    pub rate_limit_quota: Option<RateLimitQuota>,
 }
 
 impl Filter {
-    fn request_authority(&self) -> String {
-        match self.get_http_request_header(":authority") {
+    fn request_authority(&self) -> Authority {
+        let mut authority = match self.get_http_request_header(":authority") {
             None => {
                 warn!(":authority header not found");
-                String::new()
+                Authority::default()
+            }
+            Some(host) => Authority::parse(&host).unwrap_or_else(|e| {
+                warn!("{e}");
+                Authority { host, port: None }
+            }),
+        };
+        // the default port of the scheme, only needed when some policies are restricted to a port
+        if authority.port.is_none() && self.config.index.has_ports() {
+            authority.port = match self.get_http_request_header(":scheme").as_deref() {
+                Some("https") => Some(443),
+                Some("http") => Some(80),
+                _ => None,
+            };
+        }
+        authority
+    }
+
+    fn request_route(&self) -> &RequestRoute {
+        self.route.get_or_init(|| RequestRoute {
+            path: get_attribute(self, "request.url_path").unwrap_or_default(),
+            method: get_attribute(self, "request.method").unwrap_or_default(),
+            route_name: get_attribute(self, "xds.route_name").unwrap_or_default(),
+        })
+    }
+
+    pub fn resolve_property(&self, path: Vec<&str>) -> Option<Bytes> {
+        match lookup_json_attribute(&self.attributes, &path) {
+            Some(value) => Some(json_attribute_bytes(value)),
+            None => self.get_property(path),
+        }
+    }
+
+    // Values of repeated headers are joined by `,`
+    pub fn request_headers(&self) -> HashMap<String, String> {
+        self.get_http_request_headers().into_iter().fold(
+            HashMap::new(),
+            |mut headers: HashMap<String, String>, (name, value)| {
+                headers
+                    .entry(name)
+                    .and_modify(|v| {
+                        v.push(',');
+                        v.push_str(&value)
+                    })
+                    .or_insert(value);
+                headers
+            },
+        )
+    }
+
+    fn tracing_headers(&self) -> Vec<(&str, &[u8])> {
+        self.tracing_headers
+            .iter()
+            .map(|(header, value)| (header.as_str(), value.as_slice()))
+            .collect()
+    }
+
+    // The actions of the most specific policy, followed by those only declared by the others
+    fn actions_of(policies: &[Rc<Policy>]) -> VecDeque<ActionKind> {
+        let mut actions = VecDeque::new();
+        for action in policies.iter().flat_map(|rlp| rlp.actions()) {
+            if !actions.contains(action) {
+                actions.push_back(*action);
+            }
+        }
+        actions
+    }
+
+    // The policies listing the action, the most specific first
+    fn running(policies: &[Rc<Policy>], action: ActionKind) -> impl Iterator<Item = &Rc<Policy>> {
+        policies
+            .iter()
+            .filter(move |rlp| rlp.actions().contains(&action))
+    }
+
+    // Authorization is done once, by the most specific policy running it
+    fn auth_of(policies: &[Rc<Policy>]) -> Option<&AuthAction> {
+        Self::running(policies, ActionKind::Auth).find_map(|rlp| rlp.auth.as_ref())
+    }
+
+    fn process_actions(&mut self, policies: &[Rc<Policy>]) -> Action {
+        while let Some(action) = self.pending_actions.pop_front() {
+            let outcome = match action {
+                ActionKind::Auth => match Self::auth_of(policies) {
+                    Some(auth) => self.process_auth_policy(auth),
+                    None => ActionOutcome::Skipped,
+                },
+                ActionKind::RateLimit => self.process_rate_limit_policies(policies),
+            };
+            match outcome {
+                ActionOutcome::Dispatched => return Action::Pause,
+                ActionOutcome::Responded => {
+                    self.abort_actions();
+                    return Action::Pause;
+                }
+                ActionOutcome::Skipped => {}
+            }
+        }
+        Action::Continue
+    }
+
+    fn resume_actions(&mut self) {
+        if !self.pending_actions.is_empty() {
+            let policies = self.policies.clone();
+            if let Action::Pause = self.process_actions(&policies) {
+                return;
+            }
+        }
+        self.resume_http_request()
+    }
+
+    // A local response has been sent, responses of the calls still in flight are ignored
+    fn abort_actions(&mut self) {
+        self.pending_actions.clear();
+        self.grpc_calls.clear();
+    }
+
+    fn dispatch(&self, call: &GrpcCall) -> Result<u32, Status> {
+        let service = self.config.service(&call.service);
+        let (service_name, method_name) = match call.action {
+            GrpcAction::Auth => (AUTH_SERVICE_NAME, AUTH_METHOD_NAME),
+            GrpcAction::RateLimit { .. } => (RATELIMIT_SERVICE_NAME, RATELIMIT_METHOD_NAME),
+        };
+        self.dispatch_grpc_call(
+            service.cluster.as_str(),
+            service_name,
+            method_name,
+            self.tracing_headers(),
+            Some(&call.request),
+            service.timeout,
+        )
+    }
+
+    fn process_auth_policy(&mut self, auth: &AuthAction) -> ActionOutcome {
+        let auth_req = AuthService::build_check_request(self, &auth.context_extensions);
+        let call = GrpcCall {
+            action: GrpcAction::Auth,
+            service: auth.service.clone(),
+            request: Message::write_to_bytes(&auth_req).unwrap(),
+            retries: 0,
+        };
+
+        match self.dispatch(&call) {
+            Ok(call_id) => {
+                debug!(
+                    "#{} initiated gRPC call (id# {}) to Authorino",
+                    self.context_id, call_id
+                );
+                self.grpc_calls.insert(call_id, call);
+                ActionOutcome::Dispatched
             }
-            Some(host) => {
-                let split_host = host.split(':').collect::<Vec<_>>();
-                split_host[0].to_owned()
+            Err(e) => {
+                warn!("gRPC call to Authorino failed! {e:?}");
+                self.handle_dispatch_error(&call)
             }
         }
     }
 
-    fn process_rate_limit_policy(&self, rlp: &Policy) -> Action {
-        let descriptors = rlp.build_descriptors(self);
+    // Sends one request per distinct service and domain, merging the descriptors of the policies
+    fn process_rate_limit_policies(&mut self, policies: &[Rc<Policy>]) -> ActionOutcome {
+        let mut requests: Vec<(&str, &str, Vec<RateLimitDescriptor>)> = Vec::new();
+        for rlp in Self::running(policies, ActionKind::RateLimit) {
+            let descriptors = rlp.build_descriptors(self).into_vec();
+            match requests
+                .iter_mut()
+                .find(|(service, domain, _)| *service == rlp.service && *domain == rlp.domain)
+            {
+                Some((_, _, merged)) => merged.extend(descriptors),
+                None => requests.push((rlp.service.as_str(), rlp.domain.as_str(), descriptors)),
+            }
+        }
+
+        let mut outcome = ActionOutcome::Skipped;
+        for (service, domain, descriptors) in requests {
+            match self.process_rate_limit_request(service, domain, descriptors) {
+                ActionOutcome::Dispatched => outcome = ActionOutcome::Dispatched,
+                ActionOutcome::Responded => return ActionOutcome::Responded,
+                ActionOutcome::Skipped => {}
+            }
+        }
+        outcome
+    }
+
+    fn process_rate_limit_request(
+        &mut self,
+        service: &str,
+        domain: &str,
+        descriptors: Vec<RateLimitDescriptor>,
+    ) -> ActionOutcome {
         if descriptors.is_empty() {
             debug!(
                 "#{} process_rate_limit_policy: empty descriptors",
                 self.context_id
             );
-            return Action::Continue;
+            return ActionOutcome::Skipped;
         }
 
         let mut rl_req = RateLimitRequest::new();
-        rl_req.set_domain(rlp.domain.clone());
+        rl_req.set_domain(domain.to_owned());
         rl_req.set_hits_addend(1);
-        rl_req.set_descriptors(descriptors);
+        rl_req.set_descriptors(RepeatedField::from_vec(descriptors));
 
         let rl_req_serialized = Message::write_to_bytes(&rl_req).unwrap(); // TODO(rahulanand16nov): Error Handling
+        let call = GrpcCall {
+            action: GrpcAction::RateLimit {
+                domain: domain.to_owned(),
+                descriptors: rl_req.take_descriptors().into_vec(),
+            },
+            service: service.to_owned(),
+            request: rl_req_serialized,
+            retries: 0,
+        };
 
-        let rl_tracing_headers = self
-            .tracing_headers
-            .iter()
-            .map(|(header, value)| (header.as_str(), value.as_slice()))
-            .collect();
-
-        match self.dispatch_grpc_call(
-            rlp.service.as_str(),
-            RATELIMIT_SERVICE_NAME,
-            RATELIMIT_METHOD_NAME,
-            rl_tracing_headers,
-            Some(&rl_req_serialized),
-            Duration::from_secs(5),
-        ) {
+        match self.dispatch(&call) {
             Ok(call_id) => {
                 debug!(
                     "#{} initiated gRPC call (id# {}) to Limitador",
                     self.context_id, call_id
                 );
-                Action::Pause
+                self.grpc_calls.insert(call_id, call);
+                ActionOutcome::Dispatched
             }
             Err(e) => {
                 warn!("gRPC call to Limitador failed! {e:?}");
-                if let FailureMode::Deny = self.config.failure_mode {
-                    self.send_http_response(500, vec![], Some(b"Internal Server Error.\n"))
-                }
-                Action::Continue
+                self.handle_dispatch_error(&call)
             }
         }
     }
 
-    fn handle_error_on_grpc_response(&self) {
-        match &self.config.failure_mode {
-            FailureMode::Deny => {
-                self.send_http_response(500, vec![], Some(b"Internal Server Error.\n"))
-            }
-            FailureMode::Allow => self.resume_http_request(),
+    fn is_grpc_request(&self) -> bool {
+        self.get_http_request_header("content-type")
+            .is_some_and(|content_type| content_type.starts_with("application/grpc"))
+    }
+
+    // gRPC clients are answered with a `200` whose `grpc-status` carries the outcome
+    fn send_local_response(
+        &self,
+        http_status: u32,
+        grpc_status: u32,
+        message: &str,
+        headers: Vec<(&str, &str)>,
+        body: Option<&[u8]>,
+    ) {
+        if !self.is_grpc_request() {
+            self.send_http_response(http_status, headers, body);
+            return;
         }
+        let grpc_status = grpc_status.to_string();
+        let grpc_message = grpc_message(message);
+        let mut grpc_headers: Vec<(&str, &str)> = headers
+            .into_iter()
+            .filter(|(name, _)| !name.eq_ignore_ascii_case("content-type"))
+            .collect();
+        grpc_headers.push(("content-type", "application/grpc"));
+        grpc_headers.push(("grpc-status", grpc_status.as_str()));
+        grpc_headers.push(("grpc-message", grpc_message.as_str()));
+        self.send_http_response(200, grpc_headers, None);
     }
-}
 
-impl HttpContext for Filter {
-    fn on_http_request_headers(&mut self, _: usize, _: bool) -> Action {
-        debug!("#{} on_http_request_headers", self.context_id);
+    // The mode set by the most specific policy behind the call, then by the service, then globally
+    fn failure_mode(&self, call: &GrpcCall, failure: Failure) -> FailureMode {
+        let config = &self.config;
+        self.policies
+            .iter()
+            .filter(|rlp| match &call.action {
+                GrpcAction::Auth => rlp
+                    .auth
+                    .as_ref()
+                    .is_some_and(|auth| auth.service == call.service),
+                GrpcAction::RateLimit { domain, .. } => {
+                    rlp.service == call.service && rlp.domain == *domain
+                }
+            })
+            .find_map(|rlp| rlp.failure_mode.get(failure))
+            .or_else(|| config.service(&call.service).failure_mode.get(failure))
+            .or_else(|| config.failure_mode.get(failure))
+            .unwrap_or(FailureMode::Deny)
+    }
 
-        for header in TracingHeader::all() {
-            if let Some(value) = self.get_http_request_header_bytes(header.as_str()) {
-                self.tracing_headers.push((header, value))
-            }
+    fn handle_dispatch_error(&self, call: &GrpcCall) -> ActionOutcome {
+        match self.handle_error_on_grpc_response(call, Failure::DispatchError) {
+            true => ActionOutcome::Skipped,
+            false => ActionOutcome::Responded,
         }
+    }
 
-        match self
-            .config
-            .index
-            .get_longest_match_policy(self.request_authority().as_str())
-        {
-            None => {
+    // Returns whether the remaining actions should be processed
+    fn handle_error_on_grpc_response(&self, call: &GrpcCall, failure: Failure) -> bool {
+        match self.failure_mode(call, failure) {
+            FailureMode::Deny => {
                 debug!(
-                    "#{} allowing request to pass because zero descriptors generated",
-                    self.context_id
+                    "#{} denying request on {failure:?} of the call to {}",
+                    self.context_id, call.service
                 );
-                Action::Continue
-            }
-            Some(rlp) => {
-                debug!("#{} ratelimitpolicy selected {}", self.context_id, rlp.name);
-                self.process_rate_limit_policy(rlp)
+                let grpc_status = match failure {
+                    Failure::DispatchError | Failure::Timeout => GRPC_STATUS_UNAVAILABLE,
+                    Failure::MalformedResponse | Failure::Unknown => GRPC_STATUS_INTERNAL,
+                };
+                self.send_local_response(
+                    500,
+                    grpc_status,
+                    "Internal Server Error",
+                    vec![],
+                    Some(b"Internal Server Error.\n"),
+                );
+                false
             }
+            FailureMode::Allow => true,
         }
     }
 
-    fn on_http_response_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Action {
-        debug!("#{} on_http_response_headers", self.context_id);
-        for (name, value) in &self.response_headers_to_add {
-            self.add_http_response_header(name, value);
-        }
-        Action::Continue
-    }
-
-    fn on_log(&mut self) {
-        debug!("#{} completed.", self.context_id);
-    }
-}
+    fn handle_auth_response(&mut self, res_body_bytes: Bytes, call: &GrpcCall) -> bool {
+        let check_resp: CheckResponse = match Message::parse_from_bytes(&res_body_bytes) {
+            Ok(res) => res,
+            Err(e) => {
+                warn!("failed to parse grpc response body into CheckResponse message: {e}");
+                return self.handle_error_on_grpc_response(call, Failure::MalformedResponse);
+            }
+        };
 
-impl Context for Filter {
-    fn on_grpc_call_response(&mut self, token_id: u32, status_code: u32, resp_size: usize) {
-        debug!(
-            "#{} on_grpc_call_response: received gRPC call response: token: {token_id}, status: {status_code}",
-            self.context_id
-        );
+        if check_resp.get_status().get_code() != 0 {
+            let status = check_resp.get_status();
+            debug!(
+                "#{} request denied by Authorino: {}",
+                self.context_id,
+                status.get_message()
+            );
+            // The status of the check response is a gRPC code already
+            let grpc_status =
+                u32::try_from(status.get_code()).unwrap_or(GRPC_STATUS_PERMISSION_DENIED);
+            let message = match status.get_message() {
+                "" => "Forbidden",
+                message => message,
+            };
+            if check_resp.has_denied_response() {
+                let denied_response = check_resp.get_denied_response();
+                let response_headers = denied_response
+                    .get_headers()
+                    .iter()
+                    .map(|header| {
+                        (
+                            header.get_header().get_key(),
+                            header.get_header().get_value(),
+                        )
+                    })
+                    .collect();
+                self.send_local_response(
+                    AuthService::denied_status_code(denied_response),
+                    grpc_status,
+                    message,
+                    response_headers,
+                    Some(denied_response.get_body().as_bytes()),
+                );
+            } else {
+                self.send_local_response(403, grpc_status, message, vec![], None);
+            }
+            return false;
+        }
 
-        let res_body_bytes = match self.get_grpc_call_response_body(0, resp_size) {
-            Some(bytes) => bytes,
-            None => {
-                warn!("grpc response body is empty!");
-                self.handle_error_on_grpc_response();
-                return;
+        if check_resp.has_ok_response() {
+            let ok_response = check_resp.get_ok_response();
+            for header_option in ok_response.get_headers() {
+                let header = header_option.get_header();
+                if header_option.get_append().get_value() {
+                    self.add_http_request_header(header.get_key(), header.get_value());
+                } else {
+                    self.set_http_request_header(header.get_key(), Some(header.get_value()));
+                }
             }
-        };
+            for header_name in ok_response.get_headers_to_remove() {
+                self.set_http_request_header(header_name, None);
+            }
+            for header_option in ok_response.get_response_headers_to_add() {
+                let header = header_option.get_header();
+                self.response_headers_to_add
+                    .push((header.get_key().to_owned(), header.get_value().to_owned()));
+            }
+        }
+
+        // Make the outcome of the authorization available to the following actions
+        self.attributes
+            .insert("auth".to_owned(), AuthService::auth_attributes(&check_resp));
+        true
+    }
 
+    fn handle_rate_limit_response(
+        &mut self,
+        res_body_bytes: Bytes,
+        call: &GrpcCall,
+        domain: &str,
+        descriptors: &[RateLimitDescriptor],
+    ) -> bool {
         let rl_resp: RateLimitResponse = match Message::parse_from_bytes(&res_body_bytes) {
             Ok(res) => res,
             Err(e) => {
                 warn!("failed to parse grpc response body into RateLimitResponse message: {e}");
-                self.handle_error_on_grpc_response();
-                return;
+                return self.handle_error_on_grpc_response(call, Failure::MalformedResponse);
             }
         };
 
@@ -183,33 +501,247 @@ impl Context for Filter {
             RateLimitResponse {
                 overall_code: RateLimitResponse_Code::UNKNOWN,
                 ..
-            } => {
-                self.handle_error_on_grpc_response();
-                return;
-            }
+            } => self.handle_error_on_grpc_response(call, Failure::Unknown),
             RateLimitResponse {
                 overall_code: RateLimitResponse_Code::OVER_LIMIT,
                 response_headers_to_add: rl_headers,
+                statuses,
                 ..
             } => {
+                self.update_rate_limit_quota(&statuses);
+                let config = Rc::clone(&self.config);
+                let deny_response = self
+                    .policies
+                    .iter()
+                    .filter(|rlp| rlp.service == call.service && rlp.domain == domain)
+                    .find_map(|rlp| rlp.deny_response.as_ref())
+                    .unwrap_or(&config.deny_response);
+
+                let mut values = HashMap::new();
+                for entry in descriptors.iter().flat_map(|d| d.get_entries()) {
+                    values.entry(entry.get_key()).or_insert(entry.get_value());
+                }
+
                 let mut response_headers = vec![];
                 for header in &rl_headers {
                     response_headers.push((header.get_key(), header.get_value()));
                 }
-                self.send_http_response(429, response_headers, Some(b"Too Many Requests\n"));
-                return;
+                let quota_headers = self
+                    .rate_limit_quota
+                    .map(|quota| quota.headers(config.rate_limit_headers, true))
+                    .unwrap_or_default();
+                for (name, value) in &quota_headers {
+                    response_headers.push((name, value));
+                }
+                response_headers.extend(deny_response.headers());
+                self.send_local_response(
+                    deny_response.status,
+                    GRPC_STATUS_RESOURCE_EXHAUSTED,
+                    "Too Many Requests",
+                    response_headers,
+                    Some(&deny_response.body(&values)),
+                );
+                false
             }
             RateLimitResponse {
                 overall_code: RateLimitResponse_Code::OK,
                 response_headers_to_add: additional_headers,
+                statuses,
                 ..
             } => {
+                self.update_rate_limit_quota(&statuses);
                 for header in additional_headers {
                     self.response_headers_to_add
                         .push((header.key, header.value));
                 }
+                true
             }
         }
-        self.resume_http_request();
+    }
+
+    // Keeps the most restrictive quota across the rate limit responses
+    fn update_rate_limit_quota(&mut self, statuses: &[RateLimitResponse_DescriptorStatus]) {
+        if self.config.rate_limit_headers == RateLimitHeaders::None {
+            return;
+        }
+        if let Some(quota) = most_restrictive_quota(statuses) {
+            self.rate_limit_quota = Some(match self.rate_limit_quota {
+                Some(current) => current.most_restrictive(quota),
+                None => quota,
+            });
+        }
+    }
+}
+
+impl HttpContext for Filter {
+    fn on_http_request_headers(&mut self, _: usize, _: bool) -> Action {
+        debug!("#{} on_http_request_headers", self.context_id);
+
+        for header in TracingHeader::all() {
+            if let Some(value) = self.get_http_request_header_bytes(header.as_str()) {
+                self.tracing_headers.push((header, value))
+            }
+        }
+
+        self.authority = self.request_authority();
+        let config = Rc::clone(&self.config);
+        let matches =
+            config
+                .index
+                .get_matches_on_port(&self.authority.host, self.authority.port, || {
+                    self.request_route()
+                });
+        if matches.is_empty() {
+            debug!(
+                "#{} allowing request to pass because zero descriptors generated",
+                self.context_id
+            );
+            return Action::Continue;
+        }
+
+        for policy_match in &matches {
+            debug!(
+                "#{} ratelimitpolicy selected {} (hostname `{}`)",
+                self.context_id,
+                policy_match.policy.name,
+                policy_match.pattern()
+            );
+        }
+        self.policies = matches
+            .iter()
+            .map(|policy_match| Rc::clone(policy_match.policy))
+            .collect();
+        let policies = self.policies.clone();
+        self.pending_actions = Self::actions_of(&policies);
+        self.process_actions(&policies)
+    }
+
+    fn on_http_response_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Action {
+        debug!("#{} on_http_response_headers", self.context_id);
+        for (name, value) in &self.response_headers_to_add {
+            self.add_http_response_header(name, value);
+        }
+        if let Some(quota) = self.rate_limit_quota {
+            for (name, value) in quota.headers(self.config.rate_limit_headers, false) {
+                self.add_http_response_header(&name, &value);
+            }
+        }
+        Action::Continue
+    }
+
+    fn on_log(&mut self) {
+        debug!("#{} completed.", self.context_id);
+    }
+}
+
+impl Context for Filter {
+    fn on_grpc_call_response(&mut self, token_id: u32, status_code: u32, resp_size: usize) {
+        debug!(
+            "#{} on_grpc_call_response: received gRPC call response: token: {token_id}, status: {status_code}",
+            self.context_id
+        );
+
+        let mut call = match self.grpc_calls.remove(&token_id) {
+            Some(call) => call,
+            None => {
+                debug!(
+                    "#{} ignoring gRPC call response: token: {token_id}",
+                    self.context_id
+                );
+                return;
+            }
+        };
+
+        if status_code != 0
+            && self
+                .config
+                .service(&call.service)
+                .should_retry(status_code, call.retries)
+        {
+            call.retries += 1;
+            match self.dispatch(&call) {
+                Ok(call_id) => {
+                    debug!(
+                        "#{} retrying gRPC call (id# {}) to {}: retry #{}",
+                        self.context_id, call_id, call.service, call.retries
+                    );
+                    self.grpc_calls.insert(call_id, call);
+                    return;
+                }
+                Err(e) => warn!("gRPC call retry to {} failed! {e:?}", call.service),
+            }
+        }
+
+        let proceed = match self.get_grpc_call_response_body(0, resp_size) {
+            Some(res_body_bytes) => match &call.action {
+                GrpcAction::Auth => self.handle_auth_response(res_body_bytes, &call),
+                GrpcAction::RateLimit {
+                    domain,
+                    descriptors,
+                } => self.handle_rate_limit_response(res_body_bytes, &call, domain, descriptors),
+            },
+            None => {
+                warn!("grpc response body is empty!");
+                // a failed call (e.g. timed out) has no response body
+                let failure = match status_code {
+                    0 => Failure::MalformedResponse,
+                    GRPC_STATUS_DEADLINE_EXCEEDED => Failure::Timeout,
+                    _ => Failure::DispatchError,
+                };
+                self.handle_error_on_grpc_response(&call, failure)
+            }
+        };
+
+        if !proceed {
+            self.abort_actions();
+        } else if self.grpc_calls.is_empty() {
+            self.resume_actions();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(name: &str, auth: bool, actions: Vec<ActionKind>) -> Rc<Policy> {
+        let mut policy = Policy::new(
+            name.to_owned(),
+            "domain".to_owned(),
+            "limitador-cluster".to_owned(),
+            vec!["*.example.com".to_owned()],
+            Vec::new(),
+        );
+        if auth {
+            policy.auth = Some(AuthAction {
+                service: format!("{name}-authorino"),
+                context_extensions: HashMap::new(),
+            });
+        }
+        policy.actions = actions;
+        policy.compile().expect("valid policy");
+        Rc::new(policy)
+    }
+
+    #[test]
+    fn policies_only_run_their_own_actions() {
+        let policies = vec![
+            policy("limit-only", true, vec![ActionKind::RateLimit]),
+            policy("auth-only", true, vec![ActionKind::Auth]),
+        ];
+
+        assert_eq!(
+            Filter::actions_of(&policies),
+            VecDeque::from([ActionKind::RateLimit, ActionKind::Auth])
+        );
+        let limiting: Vec<&str> = Filter::running(&policies, ActionKind::RateLimit)
+            .map(|rlp| rlp.name.as_str())
+            .collect();
+        assert_eq!(limiting, vec!["limit-only"]);
+        assert_eq!(
+            Filter::auth_of(&policies).map(|auth| auth.service.as_str()),
+            Some("auth-only-authorino")
+        );
+        assert!(Filter::auth_of(&policies[..1]).is_none());
     }
 }
@@ -1,9 +1,12 @@
+use crate::authority::Authority;
 use crate::configuration::{FilterConfig, PluginConfiguration};
 use crate::filter::http_context::Filter;
 use const_format::formatcp;
 use log::{debug, error, info};
 use proxy_wasm::traits::{Context, HttpContext, RootContext};
 use proxy_wasm::types::ContextType;
+use std::cell::OnceCell;
+use std::collections::{HashMap, VecDeque};
 use std::rc::Rc;
 
 const WASM_SHIM_VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -41,6 +44,13 @@ impl RootContext for FilterRoot {
             config: Rc::clone(&self.config),
             response_headers_to_add: Vec::default(),
             tracing_headers: Vec::default(),
+            authority: Authority::default(),
+            route: OnceCell::new(),
+            policies: Vec::default(),
+            pending_actions: VecDeque::default(),
+            grpc_calls: HashMap::default(),
+            attributes: serde_json::Map::default(),
+            rate_limit_quota: None,
         }))
     }
 
@@ -61,6 +71,16 @@ impl RootContext for FilterRoot {
                             return false;
                         }
                     };
+                info!(
+                    "#{} policy index: {}",
+                    self.context_id,
+                    filter_config.index.counts()
+                );
+                info!(
+                    "#{} policy index: about {} bytes",
+                    self.context_id,
+                    filter_config.index.estimated_size()
+                );
                 self.config = Rc::new(filter_config);
             }
             Err(e) => {
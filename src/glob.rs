@@ -6,6 +6,9 @@
 // - '*': 0 or more characters
 // - '+': 1 or more characters
 //
+// With a separator, those don't match it, and '**' matches 0 or more characters, separators
+// included. A backslash then makes the following character literal.
+//
 use std::convert::TryFrom;
 
 use regex::Regex;
@@ -51,6 +54,12 @@ impl GlobPattern {
         Ok(Self(Regex::new(regex_pattern.as_str())?))
     }
 
+    pub fn with_separator(pattern: &str, separator: char) -> Result<Self, Error> {
+        let regex_pattern = Self::segments_glob_pattern(pattern, separator);
+
+        Ok(Self(Regex::new(regex_pattern.as_str())?))
+    }
+
     pub fn is_match(&self, s: &str) -> bool {
         self.0.is_match(s)
     }
@@ -69,6 +78,31 @@ impl GlobPattern {
 
         format!(r"\A{}\z", regex_pattern.as_str())
     }
+
+    pub fn segments_glob_pattern(pattern: &str, separator: char) -> String {
+        let segment = format!("[^{}]", regex::escape(separator.encode_utf8(&mut [0; 4])));
+        let mut regex_pattern = String::with_capacity(pattern.len() * 2);
+        let mut chars = pattern.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => {
+                    let literal = chars.next().unwrap_or('\\');
+                    regex_pattern.push_str(&regex::escape(literal.encode_utf8(&mut [0; 4])));
+                }
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    regex_pattern.push_str(".*");
+                }
+                '*' | '?' | '+' => {
+                    regex_pattern.push_str(&segment);
+                    regex_pattern.push(c);
+                }
+                _ => regex_pattern.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+            }
+        }
+
+        format!(r"\A{}\z", regex_pattern.as_str())
+    }
 }
 
 // Unescape previously escaped string with special characters.
@@ -205,6 +239,35 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn glob_pattern_with_separator_matches() -> Result<(), Error> {
+        let fixtures = [
+            ("/api/*/orders/**", "/api/v1/orders/42", true),
+            ("/api/*/orders/**", "/api/v1/orders/42/items", true),
+            ("/api/*/orders/**", "/api/a/b/c/orders", false),
+            ("/api/*/orders/**", "/api/a/b/orders/42", false),
+            ("/api/+/orders", "/api//orders", false),
+            ("/api/v?", "/api/v/", false),
+            ("/**", "/", true),
+            ("/\\*", "/*", true),
+            ("/\\*", "/a", false),
+            ("/a.b", "/axb", false),
+        ];
+
+        for &(pattern, input, expected) in &fixtures {
+            let glob = GlobPattern::with_separator(pattern, '/')?;
+            assert_eq!(glob.is_match(input), expected, "{pattern} on {input}");
+        }
+
+        let glob = GlobPattern::with_separator("api-*.example.com", '.')?;
+        assert!(glob.is_match("api-x.example.com"));
+        assert!(!glob.is_match("api-x.evil.example.com"));
+        let glob = GlobPattern::with_separator("api-**.example.com", '.')?;
+        assert!(glob.is_match("api-x.evil.example.com"));
+
+        Ok(())
+    }
+
     mod unescape_logic {
         use super::*;
 
@@ -0,0 +1,94 @@
+// Estimates of the memory used by the compiled configuration.
+//
+// The heap size of a value is the memory it owns beyond its own size, e.g. the bytes of a
+// string or the items of a vector. Estimates count the allocated capacity, not the allocator
+// overhead. Compiled forms whose internals aren't exposed, i.e. regular expressions, CEL
+// expressions and selector paths, are counted as the length of their source.
+//
+use std::collections::{BTreeMap, HashMap};
+use std::mem::size_of;
+
+pub trait HeapSize {
+    fn heap_size(&self) -> usize;
+}
+
+impl HeapSize for String {
+    fn heap_size(&self) -> usize {
+        self.capacity()
+    }
+}
+
+impl<T: HeapSize> HeapSize for Vec<T> {
+    fn heap_size(&self) -> usize {
+        self.capacity() * size_of::<T>() + self.iter().map(HeapSize::heap_size).sum::<usize>()
+    }
+}
+
+impl<T: HeapSize> HeapSize for Option<T> {
+    fn heap_size(&self) -> usize {
+        self.as_ref().map_or(0, HeapSize::heap_size)
+    }
+}
+
+impl<T: HeapSize> HeapSize for Box<T> {
+    fn heap_size(&self) -> usize {
+        size_of::<T>() + self.as_ref().heap_size()
+    }
+}
+
+// A control byte per bucket, along with the entry
+impl<K: HeapSize, V: HeapSize> HeapSize for HashMap<K, V> {
+    fn heap_size(&self) -> usize {
+        self.capacity() * (size_of::<(K, V)>() + 1)
+            + self
+                .iter()
+                .map(|(k, v)| k.heap_size() + v.heap_size())
+                .sum::<usize>()
+    }
+}
+
+// Nodes are counted by their entries only
+impl<K: HeapSize, V: HeapSize> HeapSize for BTreeMap<K, V> {
+    fn heap_size(&self) -> usize {
+        self.iter()
+            .map(|(k, v)| size_of::<(K, V)>() + k.heap_size() + v.heap_size())
+            .sum()
+    }
+}
+
+impl HeapSize for serde_json::Value {
+    fn heap_size(&self) -> usize {
+        match self {
+            serde_json::Value::String(s) => s.heap_size(),
+            serde_json::Value::Array(items) => items.heap_size(),
+            serde_json::Value::Object(fields) => fields
+                .iter()
+                .map(|(k, v)| {
+                    size_of::<(String, serde_json::Value)>() + k.heap_size() + v.heap_size()
+                })
+                .sum(),
+            _ => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heap_size_of_nested_values() {
+        let strings = vec!["ab".to_string(), "cde".to_string()];
+        assert_eq!(
+            strings.heap_size(),
+            strings.capacity() * size_of::<String>() + 2 + 3
+        );
+        assert_eq!(None::<String>.heap_size(), 0);
+        assert_eq!(
+            Box::new("ab".to_string()).heap_size(),
+            size_of::<String>() + 2
+        );
+        let json: serde_json::Value = serde_json::from_str(r#"{"ab": ["cde"]}"#).unwrap();
+        assert!(json.heap_size() >= 2 + 3 + size_of::<serde_json::Value>());
+    }
+}
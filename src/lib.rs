@@ -1,10 +1,15 @@
 mod attribute;
+mod authority;
+mod cel;
+mod cidr;
 mod configuration;
 mod envoy;
 mod filter;
 mod glob;
+mod heap_size;
 mod policy;
 mod policy_index;
+mod service;
 
 #[cfg(test)]
 mod tests {
@@ -1,15 +1,86 @@
-use crate::attribute::Attribute;
-use crate::configuration::{DataItem, DataType, PatternExpression};
+use crate::attribute::render_attribute;
+use crate::authority::Authority;
+use crate::cel::{descriptor_value, Predicate};
+use crate::configuration::{
+    type_of, DataItem, DataType, FailureModes, Normalization, PatternExpression,
+};
 use crate::envoy::{RateLimitDescriptor, RateLimitDescriptor_Entry};
 use crate::filter::http_context::Filter;
+use crate::heap_size::HeapSize;
 use log::debug;
-use proxy_wasm::traits::Context;
 use serde::Deserialize;
+use std::cell::OnceCell;
+use std::collections::{BTreeMap, HashMap};
+use std::mem::size_of;
 
+// Node of a condition tree, all of its parts must apply
 #[derive(Deserialize, Debug, Clone)]
-#[serde(rename_all = "camelCase")]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub struct Condition {
-    pub all_of: Vec<PatternExpression>,
+    #[serde(default)]
+    pub all_of: Vec<ConditionItem>,
+    // At least one must apply, when any
+    #[serde(default)]
+    pub any_of: Vec<ConditionItem>,
+    // Must not apply
+    #[serde(default)]
+    pub not: Option<Box<ConditionItem>>,
+    // CEL expression the request must satisfy as well
+    #[serde(default)]
+    pub predicate: Option<Predicate>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum ConditionItem {
+    Pattern(PatternExpression),
+    Condition(Condition),
+}
+
+impl Condition {
+    fn compile(&self) -> Result<(), String> {
+        if self.all_of.is_empty()
+            && self.any_of.is_empty()
+            && self.not.is_none()
+            && self.predicate.is_none()
+        {
+            return Err("condition without any `allOf`, `anyOf`, `not` or `predicate`".to_string());
+        }
+        for item in self
+            .all_of
+            .iter()
+            .chain(&self.any_of)
+            .chain(self.not.as_deref())
+        {
+            match item {
+                ConditionItem::Pattern(pattern) => pattern.compile()?,
+                ConditionItem::Condition(condition) => condition.compile()?,
+            }
+        }
+        if let Some(predicate) = &self.predicate {
+            predicate.compile()?;
+        }
+        Ok(())
+    }
+
+    // Evaluation stops as soon as the outcome is known, sparing the remaining attribute lookups.
+    // Predicates, fetching every attribute they refer to, are evaluated last.
+    fn applies<P, E>(&self, pattern_applies: &P, predicate_applies: &E) -> bool
+    where
+        P: Fn(&PatternExpression) -> bool,
+        E: Fn(&Predicate) -> bool,
+    {
+        let item_applies = |item: &ConditionItem| match item {
+            ConditionItem::Pattern(pattern_expression) => pattern_applies(pattern_expression),
+            ConditionItem::Condition(condition) => {
+                condition.applies(pattern_applies, predicate_applies)
+            }
+        };
+        self.all_of.iter().all(item_applies)
+            && (self.any_of.is_empty() || self.any_of.iter().any(item_applies))
+            && self.not.as_deref().map_or(true, |item| !item_applies(item))
+            && self.predicate.as_ref().map_or(true, predicate_applies)
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -21,14 +92,259 @@ pub struct Rule {
     pub data: Vec<DataItem>,
 }
 
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthAction {
+    // Name of the external authorization service (e.g. Authorino), or of its Envoy cluster
+    pub service: String,
+    // Additional context passed along to the authorization service
+    #[serde(default)]
+    pub context_extensions: HashMap<String, String>,
+}
+
+const DEFAULT_DENY_STATUS: u32 = 429;
+const DEFAULT_DENY_BODY: &[u8] = b"Too Many Requests\n";
+
+// Local response sent downstream when a request is rate limited.
+// In the bodies, `${key}` is replaced by the value of the descriptor entry `key`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct DenyResponse {
+    #[serde(default = "DenyResponse::default_status")]
+    pub status: u32,
+    // Plain text body
+    #[serde(default)]
+    pub body: Option<String>,
+    // JSON body, placeholders are replaced within its strings
+    #[serde(default)]
+    pub json_body: Option<serde_json::Value>,
+    // Defaults to `application/json` for a JSON body
+    #[serde(default)]
+    pub content_type: Option<String>,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+impl DenyResponse {
+    fn default_status() -> u32 {
+        DEFAULT_DENY_STATUS
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !(400..600).contains(&self.status) {
+            return Err(format!(
+                "deny response status {} is not a 4xx or 5xx code",
+                self.status
+            ));
+        }
+        if self.body.is_some() && self.json_body.is_some() {
+            return Err("deny response `body` and `jsonBody` are mutually exclusive".to_string());
+        }
+        if self.headers.keys().any(|name| name.is_empty()) {
+            return Err("deny response header without a name".to_string());
+        }
+        // the content type would be sent twice
+        let content_type_header = self
+            .headers
+            .keys()
+            .any(|name| name.eq_ignore_ascii_case("content-type"));
+        if content_type_header && (self.content_type.is_some() || self.json_body.is_some()) {
+            return Err(
+                "deny response `content-type` header conflicts with `contentType` (or the `jsonBody` default)"
+                    .to_string(),
+            );
+        }
+        Ok(())
+    }
+
+    pub fn headers(&self) -> Vec<(&str, &str)> {
+        let content_type = match (&self.content_type, &self.json_body) {
+            (Some(content_type), _) => Some(content_type.as_str()),
+            (None, Some(_)) => Some("application/json"),
+            (None, None) => None,
+        };
+        content_type
+            .map(|content_type| ("content-type", content_type))
+            .into_iter()
+            .chain(
+                self.headers
+                    .iter()
+                    .map(|(name, value)| (name.as_str(), value.as_str())),
+            )
+            .collect()
+    }
+
+    pub fn body(&self, values: &HashMap<&str, &str>) -> Vec<u8> {
+        match (&self.body, &self.json_body) {
+            (Some(body), _) => interpolate(body, values).into_bytes(),
+            (None, Some(json_body)) => {
+                serde_json::to_vec(&interpolate_json(json_body, values)).unwrap_or_default()
+            }
+            (None, None) => DEFAULT_DENY_BODY.to_vec(),
+        }
+    }
+}
+
+impl Default for DenyResponse {
+    fn default() -> Self {
+        DenyResponse {
+            status: DEFAULT_DENY_STATUS,
+            body: None,
+            json_body: None,
+            content_type: None,
+            headers: BTreeMap::new(),
+        }
+    }
+}
+
+// Replaces the `${key}` placeholders, unknown keys by an empty string
+fn interpolate(template: &str, values: &HashMap<&str, &str>) -> String {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("${") {
+        result.push_str(&rest[..start]);
+        let placeholder = &rest[start + 2..];
+        match placeholder.find('}') {
+            Some(end) => {
+                result.push_str(values.get(&placeholder[..end]).unwrap_or(&""));
+                rest = &placeholder[end + 1..];
+            }
+            None => {
+                result.push_str(&rest[start..]);
+                rest = "";
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+fn interpolate_json(value: &serde_json::Value, values: &HashMap<&str, &str>) -> serde_json::Value {
+    match value {
+        serde_json::Value::String(template) => {
+            serde_json::Value::String(interpolate(template, values))
+        }
+        serde_json::Value::Array(items) => serde_json::Value::Array(
+            items
+                .iter()
+                .map(|item| interpolate_json(item, values))
+                .collect(),
+        ),
+        serde_json::Value::Object(fields) => serde_json::Value::Object(
+            fields
+                .iter()
+                .map(|(key, field)| (key.clone(), interpolate_json(field, values)))
+                .collect(),
+        ),
+        other => other.clone(),
+    }
+}
+
+// Attributes of the request a policy can be scoped to
+#[derive(Debug, Default)]
+pub struct RequestRoute {
+    pub path: String,
+    pub method: String,
+    pub route_name: String,
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct RouteMatch {
+    // Exact match of the request path, without the query string
+    #[serde(default)]
+    pub path: Option<String>,
+    // Match of the request path by path segments, i.e. `/api` matches `/api/toys` but not `/apis`
+    #[serde(default)]
+    pub path_prefix: Option<String>,
+    // If not set, any method matches
+    #[serde(default)]
+    pub methods: Vec<String>,
+    // Name of the Envoy route (`xds.route_name`)
+    #[serde(default)]
+    pub route_name: Option<String>,
+}
+
+impl RouteMatch {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.path.is_some() && self.path_prefix.is_some() {
+            return Err("`path` and `pathPrefix` are mutually exclusive".to_string());
+        }
+        if self.path.is_none()
+            && self.path_prefix.is_none()
+            && self.methods.is_empty()
+            && self.route_name.is_none()
+        {
+            return Err("route match without any criteria".to_string());
+        }
+        match self.path.as_ref().or(self.path_prefix.as_ref()) {
+            Some(path) if !path.starts_with('/') => {
+                Err(format!("path `{path}` must start with `/`"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn matches(&self, route: &RequestRoute) -> bool {
+        self.route_name
+            .as_ref()
+            .map_or(true, |route_name| *route_name == route.route_name)
+            && self.path.as_ref().map_or(true, |path| *path == route.path)
+            && self.path_prefix.as_ref().map_or(true, |prefix| {
+                Self::path_prefix_matches(prefix, &route.path)
+            })
+            && (self.methods.is_empty()
+                || self
+                    .methods
+                    .iter()
+                    .any(|method| method.eq_ignore_ascii_case(&route.method)))
+    }
+
+    fn path_prefix_matches(prefix: &str, path: &str) -> bool {
+        match path.strip_prefix(prefix) {
+            None => false,
+            Some(rest) => rest.is_empty() || prefix.ends_with('/') || rest.starts_with('/'),
+        }
+    }
+}
+
+#[derive(Deserialize, PartialEq, Debug, Clone, Copy)]
+pub enum ActionKind {
+    #[serde(rename = "auth")]
+    Auth,
+    #[serde(rename = "ratelimit")]
+    RateLimit,
+}
+
 #[derive(Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Policy {
     pub name: String,
     pub domain: String,
+    // Name of the rate limit service, or of its Envoy cluster
     pub service: String,
     pub hostnames: Vec<String>,
+    // Restricts the policy to some routes of its hostnames. If not set, it applies to any route.
+    #[serde(default)]
+    pub routes: Vec<RouteMatch>,
     pub rules: Vec<Rule>,
+    // When set, requests are authorized before being rate limited
+    #[serde(default)]
+    pub auth: Option<AuthAction>,
+    // Order in which the actions are processed.
+    // If not set, it defaults to `auth` (when configured) followed by `ratelimit`.
+    #[serde(default)]
+    pub actions: Vec<ActionKind>,
+    // Response to requests rate limited by this policy.
+    // If not set, it defaults to the one of the plugin configuration.
+    #[serde(default)]
+    pub deny_response: Option<DenyResponse>,
+    // Overrides the failure mode of the services called for this policy
+    #[serde(default)]
+    pub failure_mode: FailureModes,
+
+    #[serde(skip_deserializing)]
+    pipeline: OnceCell<Vec<ActionKind>>,
 }
 
 impl Policy {
@@ -45,10 +361,77 @@ impl Policy {
             domain,
             service,
             hostnames,
+            routes: Vec::new(),
             rules,
+            auth: None,
+            actions: Vec::new(),
+            deny_response: None,
+            failure_mode: FailureModes::default(),
+            pipeline: OnceCell::new(),
         }
     }
 
+    pub fn compile(&self) -> Result<(), String> {
+        for hostname in &self.hostnames {
+            Authority::parse(hostname).map_err(|e| format!("policy `{}`: {e}", self.name))?;
+        }
+        for route_match in &self.routes {
+            route_match
+                .validate()
+                .map_err(|e| format!("policy `{}`: {e}", self.name))?;
+        }
+        if let Some(deny_response) = &self.deny_response {
+            deny_response
+                .validate()
+                .map_err(|e| format!("policy `{}`: {e}", self.name))?;
+        }
+        for (i, rule) in self.rules.iter().enumerate() {
+            for datum in &rule.data {
+                datum
+                    .item
+                    .compile()
+                    .map_err(|e| format!("policy `{}`: {e}", self.name))?;
+            }
+            for condition in &rule.conditions {
+                condition
+                    .compile()
+                    .map_err(|e| format!("policy `{}`, rule #{i}: {e}", self.name))?;
+            }
+        }
+
+        let pipeline = if self.actions.is_empty() {
+            match self.auth {
+                Some(_) => vec![ActionKind::Auth, ActionKind::RateLimit],
+                None => vec![ActionKind::RateLimit],
+            }
+        } else {
+            for (i, action) in self.actions.iter().enumerate() {
+                if self.actions[..i].contains(action) {
+                    return Err(format!(
+                        "policy `{}`: action {action:?} listed more than once",
+                        self.name
+                    ));
+                }
+            }
+            if self.actions.contains(&ActionKind::Auth) && self.auth.is_none() {
+                return Err(format!(
+                    "policy `{}`: `auth` action requires an `auth` section",
+                    self.name
+                ));
+            }
+            self.actions.clone()
+        };
+        self.pipeline
+            .set(pipeline)
+            .map_err(|_| format!("policy `{}` compiled twice", self.name))
+    }
+
+    pub fn actions(&self) -> &[ActionKind] {
+        self.pipeline
+            .get()
+            .expect("Policy wasn't previously compiled!")
+    }
+
     pub fn build_descriptors(
         &self,
         filter: &Filter,
@@ -74,25 +457,32 @@ impl Policy {
     }
 
     fn condition_applies(&self, filter: &Filter, condition: &Condition) -> bool {
-        condition
-            .all_of
-            .iter()
-            .all(|pattern_expression| self.pattern_expression_applies(filter, pattern_expression))
+        condition.applies(
+            &|pattern_expression| self.pattern_expression_applies(filter, pattern_expression),
+            &|predicate| self.predicate_applies(filter, predicate),
+        )
+    }
+
+    fn predicate_applies(&self, filter: &Filter, predicate: &Predicate) -> bool {
+        match predicate.eval(filter) {
+            Err(e) => {
+                debug!("#{} predicate_applies failed: {}", filter.context_id, e);
+                false
+            }
+            Ok(result) => result,
+        }
     }
 
     fn pattern_expression_applies(&self, filter: &Filter, p_e: &PatternExpression) -> bool {
         let attribute_path = p_e.path();
-        let attribute_value = match filter.get_property(attribute_path) {
-            None => {
-                debug!(
-                    "#{} pattern_expression_applies:  selector not found: {}, defaulting to ``",
-                    filter.context_id, p_e.selector
-                );
-                b"".to_vec()
-            }
-            Some(attribute_bytes) => attribute_bytes,
-        };
-        match p_e.eval(attribute_value) {
+        let attribute_value = filter.resolve_property(attribute_path);
+        if attribute_value.is_none() && !p_e.checks_presence() {
+            debug!(
+                "#{} pattern_expression_applies:  selector not found: {}, defaulting to ``",
+                filter.context_id, p_e.selector
+            );
+        }
+        match p_e.applies(attribute_value) {
             Err(e) => {
                 debug!(
                     "#{} pattern_expression_applies failed: {}",
@@ -127,7 +517,7 @@ impl Policy {
                     };
 
                     let attribute_path = selector_item.path();
-                    let value = match filter.get_property(attribute_path.tokens()) {
+                    let value = match filter.resolve_property(attribute_path.tokens()) {
                         None => {
                             debug!(
                                 "#{} build_single_descriptor: selector not found: {}",
@@ -138,10 +528,14 @@ impl Policy {
                                 Some(default_value) => default_value.clone(),
                             }
                         }
-                        // TODO(eastizle): not all fields are strings
                         // https://www.envoyproxy.io/docs/envoy/latest/intro/arch_overview/advanced/attributes
-                        Some(attribute_bytes) => Attribute::parse(attribute_bytes)
-                            .inspect_err(|e| debug!("#{} build_single_descriptor: failed to parse selector value: {}, error: {}",
+                        Some(attribute_bytes) => render_attribute(
+                            type_of(&selector_item.selector),
+                            selector_item.format,
+                            attribute_bytes,
+                        )
+                        .map(|value| Normalization::apply_all(&selector_item.normalize, value))
+                        .inspect_err(|e| debug!("#{} build_single_descriptor: failed to parse selector value: {}, error: {}",
                                     filter.context_id, attribute_path, e))
                             .ok()?,
                     };
@@ -150,6 +544,23 @@ impl Policy {
                     descriptor_entry.set_value(value);
                     entries.push(descriptor_entry);
                 }
+                DataType::Expression(expression_item) => {
+                    let value = expression_item
+                        .program()
+                        .eval(filter)
+                        .and_then(descriptor_value)
+                        .inspect_err(|e| {
+                            debug!(
+                                "#{} build_single_descriptor: failed to evaluate expression: {}, error: {}",
+                                filter.context_id, expression_item.value, e
+                            )
+                        })
+                        .ok()?; // skipping the entire descriptor
+                    let mut descriptor_entry = RateLimitDescriptor_Entry::new();
+                    descriptor_entry.set_key(expression_item.key.to_owned());
+                    descriptor_entry.set_value(value);
+                    entries.push(descriptor_entry);
+                }
             }
         }
 
@@ -158,3 +569,169 @@ impl Policy {
         Some(res)
     }
 }
+
+impl HeapSize for Policy {
+    fn heap_size(&self) -> usize {
+        self.name.heap_size()
+            + self.domain.heap_size()
+            + self.service.heap_size()
+            + self.hostnames.heap_size()
+            + self.routes.heap_size()
+            + self.rules.heap_size()
+            + self.auth.heap_size()
+            + self.actions.heap_size()
+            + self.deny_response.heap_size()
+            + self
+                .pipeline
+                .get()
+                .map_or(0, |pipeline| pipeline.capacity() * size_of::<ActionKind>())
+    }
+}
+
+impl HeapSize for ActionKind {
+    fn heap_size(&self) -> usize {
+        0
+    }
+}
+
+impl HeapSize for RouteMatch {
+    fn heap_size(&self) -> usize {
+        self.path.heap_size()
+            + self.path_prefix.heap_size()
+            + self.methods.heap_size()
+            + self.route_name.heap_size()
+    }
+}
+
+impl HeapSize for Rule {
+    fn heap_size(&self) -> usize {
+        self.conditions.heap_size() + self.data.heap_size()
+    }
+}
+
+impl HeapSize for Condition {
+    fn heap_size(&self) -> usize {
+        self.all_of.heap_size()
+            + self.any_of.heap_size()
+            + self.not.heap_size()
+            + self.predicate.heap_size()
+    }
+}
+
+impl HeapSize for ConditionItem {
+    fn heap_size(&self) -> usize {
+        match self {
+            ConditionItem::Pattern(pattern) => pattern.heap_size(),
+            ConditionItem::Condition(condition) => condition.heap_size(),
+        }
+    }
+}
+
+impl HeapSize for AuthAction {
+    fn heap_size(&self) -> usize {
+        self.service.heap_size() + self.context_extensions.heap_size()
+    }
+}
+
+impl HeapSize for DenyResponse {
+    fn heap_size(&self) -> usize {
+        self.body.heap_size()
+            + self.json_body.heap_size()
+            + self.content_type.heap_size()
+            + self.headers.heap_size()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    // Patterns apply when their value is `true`, the selectors evaluated are recorded
+    fn evaluate(condition: &Condition) -> (bool, Vec<String>) {
+        let evaluated = RefCell::new(Vec::new());
+        let applies = condition.applies(
+            &|pattern: &PatternExpression| {
+                evaluated.borrow_mut().push(pattern.selector.clone());
+                pattern.value() == "true"
+            },
+            &|_: &Predicate| true,
+        );
+        (applies, evaluated.into_inner())
+    }
+
+    fn condition(json: &str) -> Condition {
+        serde_json::from_str(json).expect("valid condition")
+    }
+
+    #[test]
+    fn condition_trees() {
+        let tree = condition(
+            r#"{
+                "allOf": [
+                    { "selector": "a", "operator": "eq", "value": "true" },
+                    { "anyOf": [
+                        { "selector": "b", "operator": "eq", "value": "false" },
+                        { "selector": "c", "operator": "eq", "value": "true" },
+                        { "selector": "d", "operator": "eq", "value": "true" }
+                    ]}
+                ],
+                "not": { "selector": "e", "operator": "eq", "value": "false" }
+            }"#,
+        );
+        assert_eq!(
+            evaluate(&tree),
+            (
+                true,
+                vec!["a", "b", "c", "e"]
+                    .into_iter()
+                    .map(String::from)
+                    .collect()
+            )
+        );
+
+        let negated = condition(
+            r#"{ "not": { "anyOf": [
+                { "selector": "a", "operator": "eq", "value": "true" },
+                { "selector": "b", "operator": "eq", "value": "true" }
+            ]}}"#,
+        );
+        assert_eq!(evaluate(&negated), (false, vec!["a".to_string()]));
+
+        let short_circuited = condition(
+            r#"{
+                "allOf": [{ "selector": "a", "operator": "eq", "value": "false" }],
+                "anyOf": [{ "selector": "b", "operator": "eq", "value": "true" }]
+            }"#,
+        );
+        assert_eq!(evaluate(&short_circuited), (false, vec!["a".to_string()]));
+
+        assert_eq!(evaluate(&condition("{}")), (true, Vec::new()));
+    }
+
+    #[test]
+    fn condition_trees_reject_empty_nodes() {
+        for json in [
+            "{}",
+            r#"{ "allOf": [], "anyOf": [] }"#,
+            r#"{ "not": {} }"#,
+            r#"{ "allOf": [{ "anyOf": [] }] }"#,
+        ] {
+            assert!(condition(json).compile().is_err(), "{json}");
+        }
+        assert!(
+            condition(r#"{ "not": { "selector": "a", "operator": "exists" } }"#)
+                .compile()
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn condition_trees_reject_unknown_nodes() {
+        assert!(serde_json::from_str::<Condition>(r#"{ "oneOf": [] }"#).is_err());
+        assert!(serde_json::from_str::<Condition>(
+            r#"{ "allOf": [{ "selector": "a", "value": "b" }] }"#
+        )
+        .is_err());
+    }
+}
@@ -1,26 +1,488 @@
-use radix_trie::Trie;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::mem::size_of;
+use std::rc::Rc;
 
-use crate::policy::Policy;
+use radix_trie::{Trie, TrieCommon};
 
+use crate::authority::Authority;
+use crate::configuration::WildcardDepth;
+use crate::glob::GlobPattern;
+use crate::heap_size::HeapSize;
+use crate::policy::{Policy, RequestRoute};
+
+// Policies of a hostname, along with the second level index of those scoped to routes
+struct HostnamePolicies {
+    // as listed by the policies, wildcards included, e.g. `*.example.com`
+    hostname: String,
+    // shared with the other hostnames of the policies
+    policies: Vec<Rc<Policy>>,
+    // port the policy at the same position is restricted to, if any
+    ports: Vec<Option<u16>>,
+    routes: RouteIndex,
+}
+
+impl HostnamePolicies {
+    fn new(hostname: String) -> Self {
+        Self {
+            hostname,
+            policies: Vec::new(),
+            ports: Vec::new(),
+            routes: RouteIndex::default(),
+        }
+    }
+
+    fn insert(&mut self, policy: Rc<Policy>, port: Option<u16>, keys: &mut RouteKeys) {
+        self.routes.insert(self.policies.len(), &policy, keys);
+        self.policies.push(policy);
+        self.ports.push(port);
+    }
+
+    // Policies restricted to the port of the request come first
+    fn matching<'r, F>(&self, port: Option<u16>, route: &F) -> Vec<PolicyMatch<'_>>
+    where
+        F: Fn() -> &'r RequestRoute,
+    {
+        let positions: Vec<usize> = if self.routes.is_scoped() {
+            self.routes.lookup(&self.policies, route())
+        } else {
+            (0..self.policies.len()).collect()
+        };
+        let (on_port, on_any_port): (Vec<usize>, Vec<usize>) = positions
+            .into_iter()
+            .filter(|position| self.ports[*position].map_or(true, |p| Some(p) == port))
+            .partition(|position| self.ports[*position].is_some());
+        on_port
+            .into_iter()
+            .chain(on_any_port)
+            .map(|position| PolicyMatch {
+                policy: &self.policies[position],
+                hostname: &self.hostname,
+                port: self.ports[position],
+            })
+            .collect()
+    }
+}
+
+// The policies are shared, only the references to them are counted
+impl HeapSize for HostnamePolicies {
+    fn heap_size(&self) -> usize {
+        self.hostname.heap_size()
+            + self.policies.capacity() * size_of::<Rc<Policy>>()
+            + self.ports.capacity() * size_of::<Option<u16>>()
+            + self.routes.heap_size()
+    }
+}
+
+// A hostname with wildcards other than a leading `*.`, e.g. `api-*.example.com`
+struct GlobHostname {
+    pattern: GlobPattern,
+    policies: HostnamePolicies,
+}
+
+// Route names, paths and prefixes used as keys, shared by the route indexes of all the hostnames
+#[derive(Default)]
+struct RouteKeys(HashSet<Rc<str>>);
+
+impl RouteKeys {
+    fn intern(&mut self, key: &str) -> Rc<str> {
+        match self.0.get(key) {
+            Some(key) => Rc::clone(key),
+            None => {
+                let key: Rc<str> = key.into();
+                self.0.insert(Rc::clone(&key));
+                key
+            }
+        }
+    }
+}
+
+// Reference counts along with the bytes of each key
+impl HeapSize for RouteKeys {
+    fn heap_size(&self) -> usize {
+        self.0.capacity() * (size_of::<Rc<str>>() + 1)
+            + self
+                .0
+                .iter()
+                .map(|key| 2 * size_of::<usize>() + key.len())
+                .sum::<usize>()
+    }
+}
+
+// Route matches are indexed by their most selective criteria, the others being checked
+// on the candidates only, so that lookups don't depend on the number of routes.
+#[derive(Default)]
+struct RouteIndex {
+    // positions of the policies applying to any route
+    unscoped: Vec<usize>,
+    // (policy, route match) positions
+    by_route_name: HashMap<Rc<str>, Vec<(usize, usize)>>,
+    by_path: HashMap<Rc<str>, Vec<(usize, usize)>>,
+    by_path_prefix: HashMap<Rc<str>, Vec<(usize, usize)>>,
+    by_method: Vec<(usize, usize)>,
+}
+
+impl RouteIndex {
+    fn insert(&mut self, position: usize, policy: &Policy, keys: &mut RouteKeys) {
+        if policy.routes.is_empty() {
+            self.unscoped.push(position);
+            return;
+        }
+        for (i, route_match) in policy.routes.iter().enumerate() {
+            let entry = (position, i);
+            if let Some(route_name) = &route_match.route_name {
+                self.by_route_name
+                    .entry(keys.intern(route_name))
+                    .or_default()
+                    .push(entry);
+            } else if let Some(path) = &route_match.path {
+                self.by_path
+                    .entry(keys.intern(path))
+                    .or_default()
+                    .push(entry);
+            } else if let Some(prefix) = &route_match.path_prefix {
+                self.by_path_prefix
+                    .entry(keys.intern(prefix))
+                    .or_default()
+                    .push(entry);
+            } else {
+                self.by_method.push(entry);
+            }
+        }
+    }
+
+    fn is_scoped(&self) -> bool {
+        !(self.by_route_name.is_empty()
+            && self.by_path.is_empty()
+            && self.by_path_prefix.is_empty()
+            && self.by_method.is_empty())
+    }
+
+    // Positions of the matching policies, those scoped to the route first
+    fn lookup(&self, policies: &[Rc<Policy>], route: &RequestRoute) -> Vec<usize> {
+        let path = route.path.as_str();
+        // prefixes are matched by path segments: `/a/b` can match `/a`, `/a/` and `/a/b`
+        let prefixes = path
+            .char_indices()
+            .filter(|(_, ch)| *ch == '/')
+            .flat_map(|(i, _)| [&path[..i], &path[..=i]])
+            .chain(std::iter::once(path));
+
+        let candidates = self
+            .by_route_name
+            .get(route.route_name.as_str())
+            .into_iter()
+            .flatten()
+            .chain(self.by_path.get(path).into_iter().flatten())
+            .chain(
+                prefixes
+                    .filter_map(|prefix| self.by_path_prefix.get(prefix))
+                    .flatten(),
+            )
+            .chain(self.by_method.iter());
+
+        let mut positions: Vec<usize> = candidates
+            .filter(|(position, i)| policies[*position].routes[*i].matches(route))
+            .map(|(position, _)| *position)
+            .collect();
+        positions.sort_unstable();
+        positions.dedup();
+        positions.extend(self.unscoped.iter());
+        positions
+    }
+}
+
+// The keys are shared, only the references to them are counted
+impl HeapSize for RouteIndex {
+    fn heap_size(&self) -> usize {
+        let positions = |map: &HashMap<Rc<str>, Vec<(usize, usize)>>| -> usize {
+            map.capacity() * (size_of::<(Rc<str>, Vec<(usize, usize)>)>() + 1)
+                + map
+                    .values()
+                    .map(|positions| positions.capacity() * size_of::<(usize, usize)>())
+                    .sum::<usize>()
+        };
+        self.unscoped.capacity() * size_of::<usize>()
+            + positions(&self.by_route_name)
+            + positions(&self.by_path)
+            + positions(&self.by_path_prefix)
+            + self.by_method.capacity() * size_of::<(usize, usize)>()
+    }
+}
+
+// A policy applying to a request, along with the hostname it was selected by
+#[derive(Debug, Clone, Copy)]
+pub struct PolicyMatch<'a> {
+    pub policy: &'a Rc<Policy>,
+    pub hostname: &'a str,
+    // when the policy is restricted to the port of the request
+    pub port: Option<u16>,
+}
+
+impl PolicyMatch<'_> {
+    // The hostname as it would be listed by the policy, e.g. `*.example.com:8443`
+    pub fn pattern(&self) -> String {
+        match (self.port, self.hostname.contains(':')) {
+            (None, _) => self.hostname.to_string(),
+            (Some(port), false) => format!("{}:{port}", self.hostname),
+            (Some(port), true) => format!("[{}]:{port}", self.hostname),
+        }
+    }
+}
+
+// Number of items in the index, policies being shared by their hostnames rather than copied
+#[derive(Debug, Default, PartialEq)]
+pub struct IndexCounts {
+    // distinct policies
+    pub policies: usize,
+    pub hostnames: usize,
+    // references to the policies, one per hostname of each policy
+    pub entries: usize,
+    // rules of the distinct policies
+    pub rules: usize,
+}
+
+impl Display for IndexCounts {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} policies ({} rules) shared by {} hostnames, {} entries",
+            self.policies, self.rules, self.hostnames, self.entries
+        )
+    }
+}
+
+// Exact hostnames are looked up in a hash map, `*.` wildcards in a trie of the reversed domains.
 pub struct PolicyIndex {
-    raw_tree: Trie<String, Policy>,
+    exact: HashMap<String, HostnamePolicies>,
+    raw_tree: Trie<String, HostnamePolicies>,
+    // matched one by one, in insertion order
+    globs: Vec<GlobHostname>,
+    route_keys: RouteKeys,
+    // whether some policies are restricted to a port
+    has_ports: bool,
+    wildcard_depth: WildcardDepth,
 }
 
 impl PolicyIndex {
     pub fn new() -> Self {
+        Self::with_wildcard_depth(WildcardDepth::default())
+    }
+
+    pub fn with_wildcard_depth(wildcard_depth: WildcardDepth) -> Self {
         Self {
+            exact: HashMap::new(),
             raw_tree: Trie::new(),
+            globs: Vec::new(),
+            route_keys: RouteKeys::default(),
+            has_ports: false,
+            wildcard_depth,
         }
     }
 
-    pub fn insert(&mut self, subdomain: &str, policy: Policy) {
-        let rev = Self::reverse_subdomain(subdomain);
-        self.raw_tree.insert(rev, policy);
+    // The hostname can restrict the policy to a port, e.g. `api.example.com:8443`
+    pub fn insert(&mut self, hostname: &str, policy: impl Into<Rc<Policy>>) {
+        let policy = policy.into();
+        let Authority {
+            host: subdomain,
+            port,
+        } = Authority::parse(hostname).expect("hostnames are validated along the policies");
+        self.has_ports |= port.is_some();
+        if Self::is_glob(&subdomain) {
+            let position = match self
+                .globs
+                .iter()
+                .position(|g| g.policies.hostname == subdomain)
+            {
+                Some(position) => position,
+                None => {
+                    self.globs.push(GlobHostname {
+                        pattern: GlobPattern::with_separator(&subdomain, '.')
+                            .expect("escaped hostnames are valid regular expressions"),
+                        policies: HostnamePolicies::new(subdomain),
+                    });
+                    self.globs.len() - 1
+                }
+            };
+            self.globs[position]
+                .policies
+                .insert(policy, port, &mut self.route_keys);
+            return;
+        }
+        if !Self::is_wildcard(&subdomain) {
+            self.exact
+                .entry(subdomain.clone())
+                .or_insert_with(|| HostnamePolicies::new(subdomain))
+                .insert(policy, port, &mut self.route_keys);
+            return;
+        }
+        let rev = Self::reverse_subdomain(&subdomain);
+        if self.raw_tree.get(&rev).is_none() {
+            self.raw_tree
+                .insert(rev.clone(), HostnamePolicies::new(subdomain));
+        }
+        self.raw_tree
+            .get_mut(&rev)
+            .expect("hostname entry was just inserted")
+            .insert(policy, port, &mut self.route_keys);
+    }
+
+    pub fn has_ports(&self) -> bool {
+        self.has_ports
+    }
+
+    // Policies of a request without a port
+    #[cfg(test)]
+    pub fn get_policies<'r, F>(&self, subdomain: &str, route: F) -> Vec<&Policy>
+    where
+        F: Fn() -> &'r RequestRoute,
+    {
+        self.get_policies_on_port(subdomain, None, route)
+    }
+
+    // Policies of `get_matches_on_port`, without the hostname they were selected by
+    #[cfg(test)]
+    pub fn get_policies_on_port<'r, F>(
+        &self,
+        subdomain: &str,
+        port: Option<u16>,
+        route: F,
+    ) -> Vec<&Policy>
+    where
+        F: Fn() -> &'r RequestRoute,
+    {
+        self.get_matches_on_port(subdomain, port, route)
+            .into_iter()
+            .map(|policy_match| policy_match.policy.as_ref())
+            .collect()
     }
 
-    pub fn get_longest_match_policy(&self, subdomain: &str) -> Option<&Policy> {
+    // All the policies applying to the subdomain, from the most to the least specific hostname:
+    // the subdomain itself, the glob hostnames, then the `*.` wildcards from the longest.
+    // For a given hostname, policies restricted to the port of the request come first, then
+    // those scoped to the request route, in insertion order. Policies restricted to another
+    // port are left out. A policy matching through several of its hostnames is only returned
+    // for the most specific one.
+    //
+    // The route of the request is only needed when some policies of the matching hostnames
+    // are scoped to routes.
+    // Each policy comes along with the hostname it was selected by.
+    pub fn get_matches_on_port<'r, F>(
+        &self,
+        subdomain: &str,
+        port: Option<u16>,
+        route: F,
+    ) -> Vec<PolicyMatch<'_>>
+    where
+        F: Fn() -> &'r RequestRoute,
+    {
         let rev = Self::reverse_subdomain(subdomain);
-        self.raw_tree.get_ancestor_value(&rev)
+        let mut matches: Vec<PolicyMatch> = Vec::new();
+        // wildcard hostnames are the prefixes of the reversed subdomain ending with a `.`
+        let mut wildcards: Vec<&str> = rev
+            .char_indices()
+            .rev()
+            .filter(|(_, ch)| *ch == '.')
+            .map(|(i, _)| &rev[..=i])
+            .collect();
+        if self.wildcard_depth == WildcardDepth::SingleLabel {
+            // the parent domain and `*`
+            let parent = wildcards.first().copied();
+            wildcards.retain(|wildcard| *wildcard == "." || Some(*wildcard) == parent);
+        }
+        let globs = self
+            .globs
+            .iter()
+            .filter(|glob| glob.pattern.is_match(subdomain))
+            .map(|glob| &glob.policies);
+        let entries = self
+            .exact
+            .get(subdomain)
+            .into_iter()
+            .chain(globs)
+            .chain(wildcards.iter().filter_map(|key| self.raw_tree.get(*key)));
+        for entry in entries {
+            for policy_match in entry.matching(port, &route) {
+                if !matches
+                    .iter()
+                    .any(|m| Rc::ptr_eq(m.policy, policy_match.policy))
+                {
+                    matches.push(policy_match);
+                }
+            }
+        }
+        matches
+    }
+
+    pub fn counts(&self) -> IndexCounts {
+        let entries: Vec<&HostnamePolicies> = self.hostname_policies().collect();
+        // rules by distinct policy
+        let policies: HashMap<*const Policy, usize> = entries
+            .iter()
+            .flat_map(|entry| entry.policies.iter())
+            .map(|policy| (Rc::as_ptr(policy), policy.rules.len()))
+            .collect();
+        IndexCounts {
+            policies: policies.len(),
+            rules: policies.values().sum(),
+            hostnames: entries.len(),
+            entries: entries.iter().map(|entry| entry.policies.len()).sum(),
+        }
+    }
+
+    // Estimated memory used by the index and its policies, in bytes, see `HeapSize`.
+    // Policies shared by several hostnames are counted once, the nodes of the trie by their entries.
+    pub fn estimated_size(&self) -> usize {
+        let entry_size = size_of::<(String, HostnamePolicies)>();
+        let exact = self.exact.capacity() * (entry_size + 1)
+            + self
+                .exact
+                .iter()
+                .map(|(hostname, entry)| hostname.heap_size() + entry.heap_size())
+                .sum::<usize>();
+        let raw_tree = self
+            .raw_tree
+            .iter()
+            .map(|(rev, entry)| entry_size + rev.heap_size() + entry.heap_size())
+            .sum::<usize>();
+        let globs = self.globs.capacity() * size_of::<GlobHostname>()
+            + self
+                .globs
+                .iter()
+                .map(|glob| glob.pattern.regex().as_str().len() + glob.policies.heap_size())
+                .sum::<usize>();
+        // reference counts along with each distinct policy
+        let policies: HashMap<*const Policy, usize> = self
+            .hostname_policies()
+            .flat_map(|entry| entry.policies.iter())
+            .map(|policy| {
+                let size = 2 * size_of::<usize>() + size_of::<Policy>() + policy.heap_size();
+                (Rc::as_ptr(policy), size)
+            })
+            .collect();
+        size_of::<Self>()
+            + exact
+            + raw_tree
+            + globs
+            + self.route_keys.heap_size()
+            + policies.values().sum::<usize>()
+    }
+
+    fn hostname_policies(&self) -> impl Iterator<Item = &HostnamePolicies> {
+        self.exact
+            .values()
+            .chain(self.raw_tree.values())
+            .chain(self.globs.iter().map(|glob| &glob.policies))
+    }
+
+    fn is_wildcard(subdomain: &str) -> bool {
+        subdomain == "*" || subdomain.starts_with("*.")
+    }
+
+    // `*` alone and leading `*.` wildcards are looked up in the tree
+    fn is_glob(subdomain: &str) -> bool {
+        let rest = subdomain.strip_prefix("*.").unwrap_or(subdomain);
+        subdomain != "*" && rest.contains(['*', '?', '+'])
     }
 
     fn reverse_subdomain(subdomain: &str) -> String {
@@ -35,10 +497,29 @@ impl PolicyIndex {
     }
 }
 
+// Lookups in an index without route scoped policies never need the route
+#[cfg(test)]
+pub fn unscoped() -> &'static RequestRoute {
+    unreachable!("the request route should not be needed")
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::policy::Policy;
-    use crate::policy_index::PolicyIndex;
+    use std::mem::size_of;
+    use std::rc::Rc;
+
+    use crate::configuration::WildcardDepth;
+    use crate::heap_size::HeapSize;
+    use crate::policy::{Policy, RequestRoute, RouteMatch, Rule};
+    use crate::policy_index::{unscoped, IndexCounts, PolicyIndex};
+
+    fn request_route(method: &str, path: &str, route_name: &str) -> RequestRoute {
+        RequestRoute {
+            path: path.to_owned(),
+            method: method.to_owned(),
+            route_name: route_name.to_owned(),
+        }
+    }
 
     fn build_ratelimit_policy(name: &str) -> Policy {
         Policy::new(
@@ -56,18 +537,18 @@ mod tests {
         let rlp1 = build_ratelimit_policy("rlp1");
         index.insert("example.com", rlp1);
 
-        let val = index.get_longest_match_policy("test.example.com");
-        assert!(val.is_none());
+        let val = index.get_policies("test.example.com", unscoped);
+        assert!(val.is_empty());
 
-        let val = index.get_longest_match_policy("other.com");
-        assert!(val.is_none());
+        let val = index.get_policies("other.com", unscoped);
+        assert!(val.is_empty());
 
-        let val = index.get_longest_match_policy("net");
-        assert!(val.is_none());
+        let val = index.get_policies("net", unscoped);
+        assert!(val.is_empty());
 
-        let val = index.get_longest_match_policy("example.com");
-        assert!(val.is_some());
-        assert_eq!(val.unwrap().name, "rlp1");
+        let val = index.get_policies("example.com", unscoped);
+        assert_eq!(val.len(), 1);
+        assert_eq!(val[0].name, "rlp1");
     }
 
     #[test]
@@ -76,8 +557,8 @@ mod tests {
         let rlp1 = build_ratelimit_policy("rlp1");
 
         index.insert("*.example.com", rlp1);
-        let val = index.get_longest_match_policy("example.com");
-        assert!(val.is_none());
+        let val = index.get_policies("example.com", unscoped);
+        assert!(val.is_empty());
     }
 
     #[test]
@@ -86,10 +567,10 @@ mod tests {
         let rlp1 = build_ratelimit_policy("rlp1");
 
         index.insert("*.example.com", rlp1);
-        let val = index.get_longest_match_policy("test.example.com");
+        let val = index.get_policies("test.example.com", unscoped);
 
-        assert!(val.is_some());
-        assert_eq!(val.unwrap().name, "rlp1");
+        assert_eq!(val.len(), 1);
+        assert_eq!(val[0].name, "rlp1");
     }
 
     #[test]
@@ -100,13 +581,14 @@ mod tests {
         let rlp2 = build_ratelimit_policy("rlp2");
         index.insert("*.example.com", rlp2);
 
-        let val = index.get_longest_match_policy("test.example.com");
-        assert!(val.is_some());
-        assert_eq!(val.unwrap().name, "rlp2");
+        let val = index.get_policies("test.example.com", unscoped);
+        assert_eq!(val.len(), 2);
+        assert_eq!(val[0].name, "rlp2");
+        assert_eq!(val[1].name, "rlp1");
 
-        let val = index.get_longest_match_policy("example.com");
-        assert!(val.is_some());
-        assert_eq!(val.unwrap().name, "rlp1");
+        let val = index.get_policies("example.com", unscoped);
+        assert_eq!(val.len(), 1);
+        assert_eq!(val[0].name, "rlp1");
     }
 
     #[test]
@@ -115,8 +597,310 @@ mod tests {
         let rlp1 = build_ratelimit_policy("rlp1");
         index.insert("*", rlp1);
 
-        let val = index.get_longest_match_policy("test.example.com");
-        assert!(val.is_some());
-        assert_eq!(val.unwrap().name, "rlp1");
+        let val = index.get_policies("test.example.com", unscoped);
+        assert_eq!(val.len(), 1);
+        assert_eq!(val[0].name, "rlp1");
+    }
+
+    #[test]
+    fn policies_ordered_by_specificity() {
+        let mut index = PolicyIndex::new();
+        index.insert("*", build_ratelimit_policy("gateway"));
+        index.insert("*.example.com", build_ratelimit_policy("wildcard"));
+        index.insert("test.example.com", build_ratelimit_policy("route-a"));
+        index.insert("test.example.com", build_ratelimit_policy("route-b"));
+        index.insert("other.example.com", build_ratelimit_policy("other"));
+
+        let val = index.get_policies("test.example.com", unscoped);
+        let names: Vec<&str> = val.iter().map(|rlp| rlp.name.as_str()).collect();
+        assert_eq!(names, vec!["route-a", "route-b", "wildcard", "gateway"]);
+
+        let val = index.get_policies("unknown.com", unscoped);
+        let names: Vec<&str> = val.iter().map(|rlp| rlp.name.as_str()).collect();
+        assert_eq!(names, vec!["gateway"]);
+    }
+
+    #[test]
+    fn glob_hostnames() {
+        let mut index = PolicyIndex::new();
+        index.insert("*", build_ratelimit_policy("gateway"));
+        index.insert("*.example.com", build_ratelimit_policy("wildcard"));
+        index.insert("api-*.example.com", build_ratelimit_policy("api"));
+        index.insert("api-v?.example.com", build_ratelimit_policy("versioned"));
+        index.insert("api-v1.example.com", build_ratelimit_policy("v1"));
+
+        let names = |subdomain: &str| -> Vec<String> {
+            index
+                .get_policies(subdomain, unscoped)
+                .iter()
+                .map(|rlp| rlp.name.clone())
+                .collect()
+        };
+
+        assert_eq!(
+            names("api-v1.example.com"),
+            vec!["v1", "api", "versioned", "wildcard", "gateway"]
+        );
+        assert_eq!(
+            names("api-eu.example.com"),
+            vec!["api", "wildcard", "gateway"]
+        );
+        assert_eq!(names("api.example.com"), vec!["wildcard", "gateway"]);
+        assert_eq!(names("api-eu.example.org"), vec!["gateway"]);
+        assert_eq!(names("api-x.evil.example.com"), vec!["wildcard", "gateway"]);
+
+        let mut index = PolicyIndex::new();
+        index.insert("api-**.example.com", build_ratelimit_policy("any-depth"));
+        assert_eq!(
+            index.get_policies("api-x.evil.example.com", unscoped)[0].name,
+            "any-depth"
+        );
+    }
+
+    #[test]
+    fn single_label_wildcards() {
+        let mut index = PolicyIndex::with_wildcard_depth(WildcardDepth::SingleLabel);
+        index.insert("*", build_ratelimit_policy("gateway"));
+        index.insert("*.com", build_ratelimit_policy("com"));
+        index.insert("*.example.com", build_ratelimit_policy("wildcard"));
+        index.insert("b.example.com", build_ratelimit_policy("b"));
+
+        let names = |subdomain: &str| -> Vec<String> {
+            index
+                .get_policies(subdomain, unscoped)
+                .iter()
+                .map(|rlp| rlp.name.clone())
+                .collect()
+        };
+
+        assert_eq!(names("a.example.com"), vec!["wildcard", "gateway"]);
+        assert_eq!(names("a.b.example.com"), vec!["gateway"]);
+        assert_eq!(names("b.example.com"), vec!["b", "wildcard", "gateway"]);
+        assert_eq!(names("example.com"), vec!["com", "gateway"]);
+        assert_eq!(names("localhost"), vec!["gateway"]);
+    }
+
+    #[test]
+    fn matched_hostnames() {
+        let mut index = PolicyIndex::new();
+        index.insert("*", build_ratelimit_policy("gateway"));
+        index.insert("*.example.com:8443", build_ratelimit_policy("wildcard"));
+        index.insert("api.example.com", build_ratelimit_policy("api"));
+        index.insert("[2001:db8::1]:8080", build_ratelimit_policy("ipv6"));
+
+        let patterns = |subdomain: &str, port: Option<u16>| -> Vec<(String, String)> {
+            index
+                .get_matches_on_port(subdomain, port, unscoped)
+                .iter()
+                .map(|m| (m.policy.name.clone(), m.pattern()))
+                .collect()
+        };
+
+        assert_eq!(
+            patterns("api.example.com", Some(8443)),
+            vec![
+                ("api".to_string(), "api.example.com".to_string()),
+                ("wildcard".to_string(), "*.example.com:8443".to_string()),
+                ("gateway".to_string(), "*".to_string()),
+            ]
+        );
+        assert_eq!(
+            patterns("2001:db8::1", Some(8080)),
+            vec![
+                ("ipv6".to_string(), "[2001:db8::1]:8080".to_string()),
+                ("gateway".to_string(), "*".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn port_restricted_policies() {
+        let mut index = PolicyIndex::new();
+        assert!(!index.has_ports());
+        index.insert("API.example.com.", build_ratelimit_policy("any-port"));
+        index.insert("api.example.com:8443", build_ratelimit_policy("admin-port"));
+        index.insert("*.example.com:443", build_ratelimit_policy("wildcard-tls"));
+        index.insert("[2001:db8::1]:8080", build_ratelimit_policy("ipv6"));
+        assert!(index.has_ports());
+
+        let names = |subdomain: &str, port: Option<u16>| -> Vec<String> {
+            index
+                .get_policies_on_port(subdomain, port, unscoped)
+                .iter()
+                .map(|rlp| rlp.name.clone())
+                .collect()
+        };
+
+        assert_eq!(
+            names("api.example.com", Some(8443)),
+            vec!["admin-port", "any-port"]
+        );
+        assert_eq!(
+            names("api.example.com", Some(443)),
+            vec!["any-port", "wildcard-tls"]
+        );
+        assert_eq!(names("api.example.com", None), vec!["any-port"]);
+        assert_eq!(names("2001:db8::1", Some(8080)), vec!["ipv6"]);
+        assert!(names("2001:db8::1", Some(80)).is_empty());
+    }
+
+    #[test]
+    fn internationalized_hostnames() {
+        let mut index = PolicyIndex::new();
+        index.insert("Bücher.example", build_ratelimit_policy("books"));
+        index.insert("*.xn--caf-dma.example", build_ratelimit_policy("cafe"));
+
+        let val = index.get_policies("xn--bcher-kva.example", unscoped);
+        assert_eq!(val.len(), 1);
+        assert_eq!(val[0].name, "books");
+
+        let val = index.get_policies("menu.xn--caf-dma.example", unscoped);
+        assert_eq!(val.len(), 1);
+        assert_eq!(val[0].name, "cafe");
+    }
+
+    #[test]
+    fn route_scoped_policies() {
+        let mut index = PolicyIndex::new();
+        let mut orders = build_ratelimit_policy("orders");
+        orders.routes = vec![RouteMatch {
+            path_prefix: Some("/api/orders".to_owned()),
+            ..Default::default()
+        }];
+        let mut create_toy = build_ratelimit_policy("create-toy");
+        create_toy.routes = vec![RouteMatch {
+            path: Some("/toys".to_owned()),
+            methods: vec!["POST".to_owned()],
+            ..Default::default()
+        }];
+        let mut admin = build_ratelimit_policy("admin");
+        admin.routes = vec![RouteMatch {
+            route_name: Some("admin-route".to_owned()),
+            ..Default::default()
+        }];
+        index.insert("example.com", build_ratelimit_policy("whole-host"));
+        index.insert("example.com", orders);
+        index.insert("example.com", create_toy);
+        index.insert("example.com", admin);
+
+        let names = |route: RequestRoute| -> Vec<String> {
+            index
+                .get_policies("example.com", || &route)
+                .iter()
+                .map(|rlp| rlp.name.clone())
+                .collect()
+        };
+
+        assert_eq!(
+            names(request_route("GET", "/api/orders/42", "")),
+            vec!["orders", "whole-host"]
+        );
+        assert_eq!(
+            names(request_route("GET", "/api/orders", "")),
+            vec!["orders", "whole-host"]
+        );
+        assert_eq!(
+            names(request_route("GET", "/api/ordersx", "")),
+            vec!["whole-host"]
+        );
+        assert_eq!(
+            names(request_route("POST", "/toys", "")),
+            vec!["create-toy", "whole-host"]
+        );
+        assert_eq!(names(request_route("GET", "/toys", "")), vec!["whole-host"]);
+        assert_eq!(
+            names(request_route("GET", "/toys", "admin-route")),
+            vec!["admin", "whole-host"]
+        );
+    }
+
+    #[test]
+    fn policies_are_shared_by_their_hostnames() {
+        let mut index = PolicyIndex::new();
+        let mut rlp1 = build_ratelimit_policy("rlp1");
+        rlp1.rules = vec![Rule {
+            conditions: Vec::new(),
+            data: Vec::new(),
+        }];
+        let rlp1 = Rc::new(rlp1);
+        for hostname in ["a.example.com", "b.example.com", "api-*.example.com"] {
+            index.insert(hostname, Rc::clone(&rlp1));
+        }
+        index.insert("a.example.com", build_ratelimit_policy("rlp2"));
+
+        assert_eq!(Rc::strong_count(&rlp1), 4);
+        assert!(std::ptr::eq(
+            index.get_policies("b.example.com", unscoped)[0],
+            index.get_policies("api-1.example.com", unscoped)[0]
+        ));
+        assert_eq!(
+            index.counts(),
+            IndexCounts {
+                policies: 2,
+                hostnames: 3,
+                entries: 4,
+                rules: 1,
+            }
+        );
+        assert_eq!(
+            index.counts().to_string(),
+            "2 policies (1 rules) shared by 3 hostnames, 4 entries"
+        );
+
+        let mut copies = PolicyIndex::new();
+        for hostname in ["a.example.com", "b.example.com", "api-*.example.com"] {
+            copies.insert(hostname, (*rlp1).clone());
+        }
+        copies.insert("a.example.com", build_ratelimit_policy("rlp2"));
+        let policy_size = 2 * size_of::<usize>() + size_of::<Policy>() + rlp1.heap_size();
+        assert_eq!(
+            copies.estimated_size() - index.estimated_size(),
+            2 * policy_size
+        );
+    }
+
+    #[test]
+    fn route_keys_are_shared_by_the_hostnames() {
+        let mut index = PolicyIndex::new();
+        let mut admin = build_ratelimit_policy("admin");
+        admin.routes = vec![RouteMatch {
+            route_name: Some("admin-route".to_owned()),
+            ..Default::default()
+        }];
+        let admin = Rc::new(admin);
+        for hostname in ["a.example.com", "*.example.com", "api-*.example.com"] {
+            index.insert(hostname, Rc::clone(&admin));
+        }
+
+        assert_eq!(index.route_keys.0.len(), 1);
+        let key = index.route_keys.0.get("admin-route").unwrap();
+        // the interned key and one per hostname
+        assert_eq!(Rc::strong_count(key), 4);
+        let route = request_route("GET", "/", "admin-route");
+        assert_eq!(
+            index.get_policies("api-1.example.com", || &route)[0].name,
+            "admin"
+        );
+    }
+
+    #[test]
+    fn policies_sharing_a_name_are_distinct() {
+        let mut index = PolicyIndex::new();
+        let mut rlp_a = build_ratelimit_policy("rlp");
+        rlp_a.domain = "a".to_owned();
+        let mut rlp_b = build_ratelimit_policy("rlp");
+        rlp_b.domain = "b".to_owned();
+        let shared = Rc::new(build_ratelimit_policy("shared"));
+        index.insert("api.example.com", rlp_a);
+        index.insert("api.example.com", Rc::clone(&shared));
+        index.insert("*.example.com", rlp_b);
+        index.insert("*.example.com", Rc::clone(&shared));
+
+        let policies: Vec<(&str, &str)> = index
+            .get_policies("api.example.com", unscoped)
+            .iter()
+            .map(|rlp| (rlp.name.as_str(), rlp.domain.as_str()))
+            .collect();
+        assert_eq!(policies, vec![("rlp", "a"), ("shared", ""), ("rlp", "b")]);
     }
 }
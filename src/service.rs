@@ -0,0 +1,33 @@
+pub(crate) mod auth;
+pub(crate) mod rate_limit;
+
+// gRPC status codes, of failed calls and answered to gRPC clients in place of HTTP error statuses
+pub const GRPC_STATUS_DEADLINE_EXCEEDED: u32 = 4;
+pub const GRPC_STATUS_PERMISSION_DENIED: u32 = 7;
+pub const GRPC_STATUS_RESOURCE_EXHAUSTED: u32 = 8;
+pub const GRPC_STATUS_INTERNAL: u32 = 13;
+pub const GRPC_STATUS_UNAVAILABLE: u32 = 14;
+
+// Percent-encodes a `grpc-message`, as required by the gRPC over HTTP/2 protocol
+pub fn grpc_message(message: &str) -> String {
+    let mut encoded = String::with_capacity(message.len());
+    for byte in message.bytes() {
+        match byte {
+            b' '..=b'~' if byte != b'%' => encoded.push(byte as char),
+            _ => encoded.push_str(format!("%{byte:02X}").as_str()),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grpc_message_is_percent_encoded() {
+        assert_eq!(grpc_message("Too Many Requests"), "Too Many Requests");
+        assert_eq!(grpc_message("100% done\n"), "100%25 done%0A");
+        assert_eq!(grpc_message("caf\u{e9}"), "caf%C3%A9");
+    }
+}
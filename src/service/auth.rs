@@ -0,0 +1,191 @@
+use crate::attribute::get_attribute;
+use crate::envoy::{
+    Address, AttributeContext, AttributeContext_HttpRequest, AttributeContext_Peer,
+    AttributeContext_Request, CheckRequest, CheckResponse, DeniedHttpResponse, SocketAddress,
+};
+use crate::filter::http_context::Filter;
+use chrono::{DateTime, FixedOffset};
+use protobuf::well_known_types::{Struct, Timestamp, Value, Value_oneof_kind};
+use protobuf::ProtobufEnum;
+use std::collections::HashMap;
+
+pub const AUTH_SERVICE_NAME: &str = "envoy.service.auth.v3.Authorization";
+pub const AUTH_METHOD_NAME: &str = "Check";
+
+// Status code sent downstream when the authorization service denies a request
+// without specifying one, as Envoy's ext_authz filter does.
+const DEFAULT_DENIED_STATUS_CODE: u32 = 403;
+
+pub struct AuthService;
+
+impl AuthService {
+    pub fn build_check_request(
+        filter: &Filter,
+        context_extensions: &HashMap<String, String>,
+    ) -> CheckRequest {
+        let mut attributes = AttributeContext::new();
+        attributes.set_request(Self::build_request(filter));
+        attributes.set_source(Self::build_peer(filter, "source.address", "source.port"));
+        attributes.set_destination(Self::build_peer(
+            filter,
+            "destination.address",
+            "destination.port",
+        ));
+        attributes.set_context_extensions(context_extensions.clone());
+
+        let mut check_request = CheckRequest::new();
+        check_request.set_attributes(attributes);
+        check_request
+    }
+
+    pub fn denied_status_code(denied_response: &DeniedHttpResponse) -> u32 {
+        match denied_response.get_status().get_code().value() {
+            code if code > 0 => code as u32,
+            _ => DEFAULT_DENIED_STATUS_CODE,
+        }
+    }
+
+    // The auth attributes exposed to the following actions: the dynamic metadata fields
+    // (e.g. `identity`, `metadata`) plus the `headers` added to the request.
+    pub fn auth_attributes(check_resp: &CheckResponse) -> serde_json::Value {
+        let mut attributes = Self::json_struct(check_resp.get_dynamic_metadata());
+        if check_resp.has_ok_response() && !attributes.contains_key("headers") {
+            let headers = check_resp
+                .get_ok_response()
+                .get_headers()
+                .iter()
+                .map(|header_option| {
+                    let header = header_option.get_header();
+                    (
+                        header.get_key().to_lowercase(),
+                        serde_json::Value::String(header.get_value().to_owned()),
+                    )
+                })
+                .collect();
+            attributes.insert("headers".to_owned(), serde_json::Value::Object(headers));
+        }
+        serde_json::Value::Object(attributes)
+    }
+
+    fn json_struct(object: &Struct) -> serde_json::Map<String, serde_json::Value> {
+        object
+            .get_fields()
+            .iter()
+            .map(|(key, value)| (key.clone(), Self::json_value(value)))
+            .collect()
+    }
+
+    fn json_value(value: &Value) -> serde_json::Value {
+        match &value.kind {
+            None | Some(Value_oneof_kind::null_value(_)) => serde_json::Value::Null,
+            Some(Value_oneof_kind::number_value(number)) => Self::json_number(*number),
+            Some(Value_oneof_kind::string_value(string)) => {
+                serde_json::Value::String(string.clone())
+            }
+            Some(Value_oneof_kind::bool_value(boolean)) => serde_json::Value::Bool(*boolean),
+            Some(Value_oneof_kind::struct_value(object)) => {
+                serde_json::Value::Object(Self::json_struct(object))
+            }
+            Some(Value_oneof_kind::list_value(list)) => {
+                serde_json::Value::Array(list.get_values().iter().map(Self::json_value).collect())
+            }
+        }
+    }
+
+    // Protobuf numbers are all doubles, integral ones are kept integral (e.g. `30`, not `30.0`)
+    fn json_number(number: f64) -> serde_json::Value {
+        let integral = number.fract() == 0.0;
+        if integral && number >= i64::MIN as f64 && number < i64::MAX as f64 {
+            serde_json::Value::from(number as i64)
+        } else if integral && number >= 0.0 && number < u64::MAX as f64 {
+            serde_json::Value::from(number as u64)
+        } else {
+            serde_json::Number::from_f64(number)
+                .map(serde_json::Value::Number)
+                .unwrap_or(serde_json::Value::Null)
+        }
+    }
+
+    fn build_request(filter: &Filter) -> AttributeContext_Request {
+        let mut http = AttributeContext_HttpRequest::new();
+        http.set_headers(filter.request_headers());
+        http.set_id(get_attribute(filter, "request.id").unwrap_or_default());
+        http.set_method(get_attribute(filter, "request.method").unwrap_or_default());
+        http.set_host(get_attribute(filter, "request.host").unwrap_or_default());
+        http.set_scheme(get_attribute(filter, "request.scheme").unwrap_or_default());
+        http.set_path(get_attribute(filter, "request.path").unwrap_or_default());
+        http.set_protocol(get_attribute(filter, "request.protocol").unwrap_or_default());
+
+        let mut request = AttributeContext_Request::new();
+        if let Ok(time) = get_attribute::<DateTime<FixedOffset>>(filter, "request.time") {
+            let mut timestamp = Timestamp::new();
+            timestamp.set_seconds(time.timestamp());
+            timestamp.set_nanos(time.timestamp_subsec_nanos() as i32);
+            request.set_time(timestamp);
+        }
+        request.set_http(http);
+        request
+    }
+
+    fn build_peer(filter: &Filter, address_attr: &str, port_attr: &str) -> AttributeContext_Peer {
+        let mut socket_address = SocketAddress::new();
+        let port = get_attribute::<i64>(filter, port_attr).ok();
+        if let Ok(address) = get_attribute::<String>(filter, address_attr) {
+            // Envoy reports the address as `ip:port`, while the peer expects the ip only
+            let host = match port {
+                Some(port) => address
+                    .strip_suffix(format!(":{port}").as_str())
+                    .unwrap_or(address.as_str()),
+                None => address.as_str(),
+            };
+            socket_address.set_address(host.trim_matches(|c| c == '[' || c == ']').to_owned());
+        }
+        if let Some(port) = port {
+            socket_address.set_port_value(port as u32);
+        }
+
+        let mut address = Address::new();
+        address.set_socket_address(socket_address);
+        let mut peer = AttributeContext_Peer::new();
+        peer.set_address(address);
+        peer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn number(number: f64) -> Value {
+        let mut value = Value::new();
+        value.set_number_value(number);
+        value
+    }
+
+    #[test]
+    fn json_value_keeps_integral_numbers_integral() {
+        assert_eq!(AuthService::json_value(&number(30.0)).to_string(), "30");
+        assert_eq!(AuthService::json_value(&number(-2.0)).to_string(), "-2");
+        assert_eq!(
+            AuthService::json_value(&number(1e19)).to_string(),
+            "10000000000000000000"
+        );
+        assert_eq!(AuthService::json_value(&number(1.5)).to_string(), "1.5");
+        let too_large = AuthService::json_value(&number(1e20));
+        assert!(too_large.is_f64());
+        assert_eq!(too_large.as_f64(), Some(1e20));
+        assert_eq!(
+            AuthService::json_value(&number(f64::NAN)),
+            serde_json::Value::Null
+        );
+    }
+
+    #[test]
+    fn json_value_of_nested_numbers() {
+        let mut object = Struct::new();
+        object.mut_fields().insert("age".to_owned(), number(30.0));
+        let mut value = Value::new();
+        value.set_struct_value(object);
+        assert_eq!(AuthService::json_value(&value).to_string(), r#"{"age":30}"#);
+    }
+}
@@ -0,0 +1,221 @@
+use crate::configuration::RateLimitHeaders;
+use crate::envoy::{
+    RateLimitResponse_Code, RateLimitResponse_DescriptorStatus, RateLimitResponse_RateLimit_Unit,
+};
+
+pub const RATELIMIT_SERVICE_NAME: &str = "envoy.service.ratelimit.v3.RateLimitService";
+pub const RATELIMIT_METHOD_NAME: &str = "ShouldRateLimit";
+
+// Quota of the most restrictive limit applied to a request, as reported by the rate limit service
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimitQuota {
+    pub limit: u32,
+    // Length of the limit's time window, in seconds
+    pub window: u64,
+    pub remaining: u32,
+    // Seconds until the quota is reset
+    pub reset: u64,
+}
+
+impl RateLimitQuota {
+    // Statuses without a limit (e.g. descriptors not matching any limit) are ignored
+    pub fn from_status(status: &RateLimitResponse_DescriptorStatus) -> Option<Self> {
+        if !status.has_current_limit() {
+            return None;
+        }
+        let current_limit = status.get_current_limit();
+        let window = match current_limit.get_unit() {
+            RateLimitResponse_RateLimit_Unit::SECOND => 1,
+            RateLimitResponse_RateLimit_Unit::MINUTE => 60,
+            RateLimitResponse_RateLimit_Unit::HOUR => 60 * 60,
+            RateLimitResponse_RateLimit_Unit::DAY => 24 * 60 * 60,
+            RateLimitResponse_RateLimit_Unit::UNKNOWN => 0,
+        };
+        let reset = if status.has_duration_until_reset() {
+            let duration = status.get_duration_until_reset();
+            // rounded up, so clients do not retry before the reset
+            duration.get_seconds().max(0) as u64 + u64::from(duration.get_nanos() > 0)
+        } else {
+            window
+        };
+        let remaining = match status.get_code() {
+            RateLimitResponse_Code::OVER_LIMIT => 0,
+            _ => status.get_limit_remaining(),
+        };
+        Some(RateLimitQuota {
+            limit: current_limit.get_requests_per_unit(),
+            window,
+            remaining,
+            reset,
+        })
+    }
+
+    // The fewest requests remaining, then the latest reset
+    pub fn most_restrictive(self, other: Self) -> Self {
+        if (other.remaining, std::cmp::Reverse(other.reset))
+            < (self.remaining, std::cmp::Reverse(self.reset))
+        {
+            other
+        } else {
+            self
+        }
+    }
+
+    pub fn headers(&self, format: RateLimitHeaders, over_limit: bool) -> Vec<(String, String)> {
+        let mut headers = match format {
+            RateLimitHeaders::None => return Vec::new(),
+            RateLimitHeaders::Draft => vec![
+                (
+                    "RateLimit-Limit".to_string(),
+                    format!("{}, {};w={}", self.limit, self.limit, self.window),
+                ),
+                (
+                    "RateLimit-Remaining".to_string(),
+                    self.remaining.to_string(),
+                ),
+                ("RateLimit-Reset".to_string(), self.reset.to_string()),
+            ],
+            RateLimitHeaders::Legacy => vec![
+                ("X-RateLimit-Limit".to_string(), self.limit.to_string()),
+                (
+                    "X-RateLimit-Remaining".to_string(),
+                    self.remaining.to_string(),
+                ),
+                ("X-RateLimit-Reset".to_string(), self.reset.to_string()),
+            ],
+        };
+        if over_limit {
+            headers.push(("Retry-After".to_string(), self.reset.to_string()));
+        }
+        headers
+    }
+}
+
+// The most restrictive quota among the statuses of a response
+pub fn most_restrictive_quota(
+    statuses: &[RateLimitResponse_DescriptorStatus],
+) -> Option<RateLimitQuota> {
+    statuses
+        .iter()
+        .filter_map(RateLimitQuota::from_status)
+        .reduce(RateLimitQuota::most_restrictive)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envoy::RateLimitResponse_RateLimit;
+    use protobuf::well_known_types::Duration;
+
+    fn status(
+        code: RateLimitResponse_Code,
+        limit: u32,
+        unit: RateLimitResponse_RateLimit_Unit,
+        remaining: u32,
+        reset: Option<(i64, i32)>,
+    ) -> RateLimitResponse_DescriptorStatus {
+        let mut current_limit = RateLimitResponse_RateLimit::new();
+        current_limit.set_requests_per_unit(limit);
+        current_limit.set_unit(unit);
+        let mut status = RateLimitResponse_DescriptorStatus::new();
+        status.set_code(code);
+        status.set_current_limit(current_limit);
+        status.set_limit_remaining(remaining);
+        if let Some((seconds, nanos)) = reset {
+            let mut duration = Duration::new();
+            duration.set_seconds(seconds);
+            duration.set_nanos(nanos);
+            status.set_duration_until_reset(duration);
+        }
+        status
+    }
+
+    #[test]
+    fn most_restrictive_quota_of_statuses() {
+        let statuses = vec![
+            RateLimitResponse_DescriptorStatus::new(),
+            status(
+                RateLimitResponse_Code::OK,
+                100,
+                RateLimitResponse_RateLimit_Unit::HOUR,
+                3,
+                Some((1200, 0)),
+            ),
+            status(
+                RateLimitResponse_Code::OK,
+                10,
+                RateLimitResponse_RateLimit_Unit::MINUTE,
+                3,
+                Some((41, 500)),
+            ),
+        ];
+        assert_eq!(
+            most_restrictive_quota(&statuses),
+            Some(RateLimitQuota {
+                limit: 100,
+                window: 3600,
+                remaining: 3,
+                reset: 1200,
+            })
+        );
+
+        let statuses = vec![
+            status(
+                RateLimitResponse_Code::OK,
+                100,
+                RateLimitResponse_RateLimit_Unit::HOUR,
+                3,
+                Some((1200, 0)),
+            ),
+            status(
+                RateLimitResponse_Code::OVER_LIMIT,
+                10,
+                RateLimitResponse_RateLimit_Unit::MINUTE,
+                1,
+                None,
+            ),
+        ];
+        assert_eq!(
+            most_restrictive_quota(&statuses),
+            Some(RateLimitQuota {
+                limit: 10,
+                window: 60,
+                remaining: 0,
+                reset: 60,
+            })
+        );
+
+        assert_eq!(
+            most_restrictive_quota(&[RateLimitResponse_DescriptorStatus::new()]),
+            None
+        );
+    }
+
+    #[test]
+    fn quota_headers() {
+        let quota = RateLimitQuota {
+            limit: 10,
+            window: 60,
+            remaining: 0,
+            reset: 42,
+        };
+        assert!(quota.headers(RateLimitHeaders::None, true).is_empty());
+        assert_eq!(
+            quota.headers(RateLimitHeaders::Draft, false),
+            vec![
+                ("RateLimit-Limit".to_string(), "10, 10;w=60".to_string()),
+                ("RateLimit-Remaining".to_string(), "0".to_string()),
+                ("RateLimit-Reset".to_string(), "42".to_string()),
+            ]
+        );
+        assert_eq!(
+            quota.headers(RateLimitHeaders::Legacy, true),
+            vec![
+                ("X-RateLimit-Limit".to_string(), "10".to_string()),
+                ("X-RateLimit-Remaining".to_string(), "0".to_string()),
+                ("X-RateLimit-Reset".to_string(), "42".to_string()),
+                ("Retry-After".to_string(), "42".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,351 @@
+use proxy_wasm_test_framework::tester;
+use proxy_wasm_test_framework::types::{Action, BufferType, LogLevel, MapType, ReturnType};
+use serial_test::serial;
+use std::path::Path;
+
+fn wasm_module() -> String {
+    let wasm_file = Path::new("target/wasm32-unknown-unknown/release/wasm_shim.wasm");
+    assert!(
+        wasm_file.exists(),
+        "Run `cargo build --release --target=wasm32-unknown-unknown` first"
+    );
+    wasm_file.to_str().unwrap().to_string()
+}
+
+const CONFIG: &str = r#"{
+    "failureMode": "deny",
+    "rateLimitPolicies": [
+    {
+        "name": "some-name",
+        "domain": "RLS-domain",
+        "service": "limitador-cluster",
+        "hostnames": ["*.toystore.com"],
+        "rules": [],
+        "auth": {
+            "service": "authorino-cluster"
+        },
+        "actions": ["auth"]
+    }]
+}"#;
+
+// Everything up to the dispatch of the `Check` request with id 42
+fn check_request(module: &mut tester::Tester, cfg: &str, index: &str) {
+    module
+        .call_start()
+        .execute_and_expect(ReturnType::None)
+        .unwrap();
+
+    let root_context = 1;
+    module
+        .call_proxy_on_context_create(root_context, 0)
+        .expect_log(Some(LogLevel::Info), Some("#1 set_root_context"))
+        .execute_and_expect(ReturnType::None)
+        .unwrap();
+    module
+        .call_proxy_on_configure(root_context, 0)
+        .expect_log(Some(LogLevel::Info), Some("#1 on_configure"))
+        .expect_get_buffer_bytes(Some(BufferType::PluginConfiguration))
+        .returning(Some(cfg.as_bytes()))
+        .expect_log(Some(LogLevel::Info), None)
+        .expect_log(Some(LogLevel::Info), Some(index))
+        // the estimated size depends on the pointer width of the target
+        .expect_log(Some(LogLevel::Info), None)
+        .execute_and_expect(ReturnType::Bool(true))
+        .unwrap();
+
+    let http_context = 2;
+    module
+        .call_proxy_on_context_create(http_context, root_context)
+        .expect_log(Some(LogLevel::Debug), Some("#2 create_http_context"))
+        .execute_and_expect(ReturnType::None)
+        .unwrap();
+
+    module
+        .call_proxy_on_request_headers(http_context, 0, false)
+        .expect_log(Some(LogLevel::Debug), Some("#2 on_http_request_headers"))
+        .expect_get_header_map_value(Some(MapType::HttpRequestHeaders), Some("traceparent"))
+        .returning(None)
+        .expect_get_header_map_value(Some(MapType::HttpRequestHeaders), Some("tracestate"))
+        .returning(None)
+        .expect_get_header_map_value(Some(MapType::HttpRequestHeaders), Some("baggage"))
+        .returning(None)
+        .expect_get_header_map_value(Some(MapType::HttpRequestHeaders), Some(":authority"))
+        .returning(Some("cars.toystore.com"))
+        .expect_log(
+            Some(LogLevel::Debug),
+            Some("#2 ratelimitpolicy selected some-name (hostname `*.toystore.com`)"),
+        )
+        .expect_get_header_map_pairs(Some(MapType::HttpRequestHeaders))
+        .returning(Some(vec![
+            (":authority", "cars.toystore.com"),
+            (":method", "GET"),
+            (":path", "/admin/toy"),
+            ("authorization", "APIKEY secret"),
+        ]))
+        .expect_get_property(Some(vec!["request", "id"]))
+        .returning(None)
+        .expect_get_property(Some(vec!["request", "method"]))
+        .returning(Some("GET".as_bytes()))
+        .expect_get_property(Some(vec!["request", "host"]))
+        .returning(Some("cars.toystore.com".as_bytes()))
+        .expect_get_property(Some(vec!["request", "scheme"]))
+        .returning(Some("http".as_bytes()))
+        .expect_get_property(Some(vec!["request", "path"]))
+        .returning(Some("/admin/toy".as_bytes()))
+        .expect_get_property(Some(vec!["request", "protocol"]))
+        .returning(Some("HTTP/1.1".as_bytes()))
+        .expect_get_property(Some(vec!["request", "time"]))
+        .returning(None)
+        .expect_get_property(Some(vec!["source", "port"]))
+        .returning(None)
+        .expect_get_property(Some(vec!["source", "address"]))
+        .returning(None)
+        .expect_get_property(Some(vec!["destination", "port"]))
+        .returning(None)
+        .expect_get_property(Some(vec!["destination", "address"]))
+        .returning(None)
+        .expect_grpc_call(
+            Some("authorino-cluster"),
+            Some("envoy.service.auth.v3.Authorization"),
+            Some("Check"),
+            Some(&[0, 0, 0, 0]),
+            None,
+            Some(5000),
+        )
+        .returning(Some(42))
+        .expect_log(
+            Some(LogLevel::Debug),
+            Some("#2 initiated gRPC call (id# 42) to Authorino"),
+        )
+        .execute_and_expect(ReturnType::Action(Action::Pause))
+        .unwrap();
+}
+
+#[test]
+#[serial]
+fn it_authorizes() {
+    let args = tester::MockSettings {
+        wasm_path: wasm_module(),
+        quiet: false,
+        allow_unexpected: false,
+    };
+    let mut module = tester::mock(args).unwrap();
+    check_request(
+        &mut module,
+        CONFIG,
+        "#1 policy index: 1 policies (0 rules) shared by 1 hostnames, 1 entries",
+    );
+
+    let http_context = 2;
+    // ok_response adding `x-auth-user: alice`, appending `x-auth-groups: admins`,
+    // removing `authorization` and adding `x-auth-id: a1` to the response
+    let grpc_response: [u8; 130] = [
+        10, 0, 34, 35, 10, 33, 10, 8, 105, 100, 101, 110, 116, 105, 116, 121, 18, 21, 42, 19, 10,
+        17, 10, 6, 117, 115, 101, 114, 105, 100, 18, 7, 26, 5, 97, 108, 105, 99, 101, 26, 89, 18,
+        22, 10, 20, 10, 11, 120, 45, 97, 117, 116, 104, 45, 117, 115, 101, 114, 18, 5, 97, 108,
+        105, 99, 101, 18, 29, 10, 23, 10, 13, 120, 45, 97, 117, 116, 104, 45, 103, 114, 111, 117,
+        112, 115, 18, 6, 97, 100, 109, 105, 110, 115, 18, 2, 8, 1, 42, 13, 97, 117, 116, 104, 111,
+        114, 105, 122, 97, 116, 105, 111, 110, 50, 17, 10, 15, 10, 9, 120, 45, 97, 117, 116, 104,
+        45, 105, 100, 18, 2, 97, 49,
+    ];
+    module
+        .call_proxy_on_grpc_receive(http_context, 42, grpc_response.len() as i32)
+        .expect_log(
+            Some(LogLevel::Debug),
+            Some("#2 on_grpc_call_response: received gRPC call response: token: 42, status: 0"),
+        )
+        .expect_get_buffer_bytes(Some(BufferType::GrpcReceiveBuffer))
+        .returning(Some(&grpc_response))
+        .expect_replace_header_map_value(
+            Some(MapType::HttpRequestHeaders),
+            Some("x-auth-user"),
+            Some("alice"),
+        )
+        .expect_add_header_map_value(
+            Some(MapType::HttpRequestHeaders),
+            Some("x-auth-groups"),
+            Some("admins"),
+        )
+        .expect_remove_header_map_value(Some(MapType::HttpRequestHeaders), Some("authorization"))
+        .execute_and_expect(ReturnType::None)
+        .unwrap();
+
+    module
+        .call_proxy_on_response_headers(http_context, 0, false)
+        .expect_log(Some(LogLevel::Debug), Some("#2 on_http_response_headers"))
+        .expect_add_header_map_value(
+            Some(MapType::HttpResponseHeaders),
+            Some("x-auth-id"),
+            Some("a1"),
+        )
+        .execute_and_expect(ReturnType::Action(Action::Continue))
+        .unwrap();
+}
+
+#[test]
+#[serial]
+fn it_denies() {
+    let args = tester::MockSettings {
+        wasm_path: wasm_module(),
+        quiet: false,
+        allow_unexpected: false,
+    };
+    let mut module = tester::mock(args).unwrap();
+    check_request(
+        &mut module,
+        CONFIG,
+        "#1 policy index: 1 policies (0 rules) shared by 1 hostnames, 1 entries",
+    );
+
+    let http_context = 2;
+    // status `7` (permission denied) with a `401` denied_response
+    let grpc_response: [u8; 92] = [
+        10, 16, 8, 7, 18, 12, 85, 110, 97, 117, 116, 104, 111, 114, 105, 122, 101, 100, 18, 72, 10,
+        3, 8, 145, 3, 18, 50, 10, 48, 10, 16, 87, 87, 87, 45, 65, 117, 116, 104, 101, 110, 116,
+        105, 99, 97, 116, 101, 18, 28, 65, 80, 73, 75, 69, 89, 32, 114, 101, 97, 108, 109, 61, 34,
+        97, 112, 105, 45, 107, 101, 121, 45, 117, 115, 101, 114, 115, 34, 26, 13, 85, 110, 97, 117,
+        116, 104, 111, 114, 105, 122, 101, 100, 10,
+    ];
+    module
+        .call_proxy_on_grpc_receive(http_context, 42, grpc_response.len() as i32)
+        .expect_log(
+            Some(LogLevel::Debug),
+            Some("#2 on_grpc_call_response: received gRPC call response: token: 42, status: 0"),
+        )
+        .expect_get_buffer_bytes(Some(BufferType::GrpcReceiveBuffer))
+        .returning(Some(&grpc_response))
+        .expect_log(
+            Some(LogLevel::Debug),
+            Some("#2 request denied by Authorino: Unauthorized"),
+        )
+        .expect_get_header_map_value(Some(MapType::HttpRequestHeaders), Some("content-type"))
+        .returning(None)
+        .expect_send_local_response(
+            Some(401),
+            Some("Unauthorized\n"),
+            Some(vec![("WWW-Authenticate", "APIKEY realm=\"api-key-users\"")]),
+            None,
+        )
+        .execute_and_expect(ReturnType::None)
+        .unwrap();
+
+    module
+        .call_proxy_on_response_headers(http_context, 0, false)
+        .expect_log(Some(LogLevel::Debug), Some("#2 on_http_response_headers"))
+        .execute_and_expect(ReturnType::Action(Action::Continue))
+        .unwrap();
+}
+
+#[test]
+#[serial]
+fn it_rate_limits_by_identity() {
+    let args = tester::MockSettings {
+        wasm_path: wasm_module(),
+        quiet: false,
+        allow_unexpected: false,
+    };
+    let mut module = tester::mock(args).unwrap();
+    let cfg = r#"{
+        "failureMode": "deny",
+        "rateLimitPolicies": [
+        {
+            "name": "some-name",
+            "domain": "RLS-domain",
+            "service": "limitador-cluster",
+            "hostnames": ["*.toystore.com"],
+            "rules": [
+            {
+                "data": [
+                {
+                    "selector": {
+                        "selector": "auth.identity.userid",
+                        "key": "user"
+                    }
+                }
+                ]
+            }],
+            "auth": {
+                "service": "authorino-cluster"
+            },
+            "actions": ["auth", "ratelimit"]
+        }]
+    }"#;
+    check_request(
+        &mut module,
+        cfg,
+        "#1 policy index: 1 policies (1 rules) shared by 1 hostnames, 1 entries",
+    );
+
+    let http_context = 2;
+    // ok_response with `identity.userid: alice` in the dynamic metadata
+    let grpc_response: [u8; 130] = [
+        10, 0, 34, 35, 10, 33, 10, 8, 105, 100, 101, 110, 116, 105, 116, 121, 18, 21, 42, 19, 10,
+        17, 10, 6, 117, 115, 101, 114, 105, 100, 18, 7, 26, 5, 97, 108, 105, 99, 101, 26, 89, 18,
+        22, 10, 20, 10, 11, 120, 45, 97, 117, 116, 104, 45, 117, 115, 101, 114, 18, 5, 97, 108,
+        105, 99, 101, 18, 29, 10, 23, 10, 13, 120, 45, 97, 117, 116, 104, 45, 103, 114, 111, 117,
+        112, 115, 18, 6, 97, 100, 109, 105, 110, 115, 18, 2, 8, 1, 42, 13, 97, 117, 116, 104, 111,
+        114, 105, 122, 97, 116, 105, 111, 110, 50, 17, 10, 15, 10, 9, 120, 45, 97, 117, 116, 104,
+        45, 105, 100, 18, 2, 97, 49,
+    ];
+    module
+        .call_proxy_on_grpc_receive(http_context, 42, grpc_response.len() as i32)
+        .expect_log(
+            Some(LogLevel::Debug),
+            Some("#2 on_grpc_call_response: received gRPC call response: token: 42, status: 0"),
+        )
+        .expect_get_buffer_bytes(Some(BufferType::GrpcReceiveBuffer))
+        .returning(Some(&grpc_response))
+        .expect_replace_header_map_value(
+            Some(MapType::HttpRequestHeaders),
+            Some("x-auth-user"),
+            Some("alice"),
+        )
+        .expect_add_header_map_value(
+            Some(MapType::HttpRequestHeaders),
+            Some("x-auth-groups"),
+            Some("admins"),
+        )
+        .expect_remove_header_map_value(Some(MapType::HttpRequestHeaders), Some("authorization"))
+        // descriptor `user: alice`
+        .expect_grpc_call(
+            Some("limitador-cluster"),
+            Some("envoy.service.ratelimit.v3.RateLimitService"),
+            Some("ShouldRateLimit"),
+            Some(&[0, 0, 0, 0]),
+            Some(&[
+                10, 10, 82, 76, 83, 45, 100, 111, 109, 97, 105, 110, 18, 15, 10, 13, 10, 4, 117,
+                115, 101, 114, 18, 5, 97, 108, 105, 99, 101, 24, 1,
+            ]),
+            Some(5000),
+        )
+        .returning(Some(43))
+        .expect_log(
+            Some(LogLevel::Debug),
+            Some("#2 initiated gRPC call (id# 43) to Limitador"),
+        )
+        .execute_and_expect(ReturnType::None)
+        .unwrap();
+
+    let grpc_response: [u8; 2] = [8, 1];
+    module
+        .call_proxy_on_grpc_receive(http_context, 43, grpc_response.len() as i32)
+        .expect_log(
+            Some(LogLevel::Debug),
+            Some("#2 on_grpc_call_response: received gRPC call response: token: 43, status: 0"),
+        )
+        .expect_get_buffer_bytes(Some(BufferType::GrpcReceiveBuffer))
+        .returning(Some(&grpc_response))
+        .execute_and_expect(ReturnType::None)
+        .unwrap();
+
+    module
+        .call_proxy_on_response_headers(http_context, 0, false)
+        .expect_log(Some(LogLevel::Debug), Some("#2 on_http_response_headers"))
+        .expect_add_header_map_value(
+            Some(MapType::HttpResponseHeaders),
+            Some("x-auth-id"),
+            Some("a1"),
+        )
+        .execute_and_expect(ReturnType::Action(Action::Continue))
+        .unwrap();
+}
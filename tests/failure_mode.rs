@@ -0,0 +1,217 @@
+use proxy_wasm_test_framework::tester;
+use proxy_wasm_test_framework::types::{Action, BufferType, LogLevel, MapType, ReturnType};
+use serial_test::serial;
+use std::path::Path;
+
+fn wasm_module() -> String {
+    let wasm_file = Path::new("target/wasm32-unknown-unknown/release/wasm_shim.wasm");
+    assert!(
+        wasm_file.exists(),
+        "Run `cargo build --release --target=wasm32-unknown-unknown` first"
+    );
+    wasm_file.to_str().unwrap().to_string()
+}
+
+const CONFIG: &str = r#"{
+    "failureMode": {
+        "timeout": "allow",
+        "default": "deny"
+    },
+    "rateLimitPolicies": [
+    {
+        "name": "some-name",
+        "domain": "RLS-domain",
+        "service": "limitador-cluster",
+        "hostnames": ["*.toystore.com"],
+        "rules": [
+        {
+            "data": [ { "static": { "key": "admin", "value": "1" } } ]
+        }]
+    }]
+}"#;
+
+// Everything up to the dispatch of the `RateLimitRequest` with id 42
+fn rate_limit_request(module: &mut tester::Tester, cfg: &str) {
+    module
+        .call_start()
+        .execute_and_expect(ReturnType::None)
+        .unwrap();
+
+    let root_context = 1;
+    module
+        .call_proxy_on_context_create(root_context, 0)
+        .expect_log(Some(LogLevel::Info), Some("#1 set_root_context"))
+        .execute_and_expect(ReturnType::None)
+        .unwrap();
+    module
+        .call_proxy_on_configure(root_context, 0)
+        .expect_log(Some(LogLevel::Info), Some("#1 on_configure"))
+        .expect_get_buffer_bytes(Some(BufferType::PluginConfiguration))
+        .returning(Some(cfg.as_bytes()))
+        .expect_log(Some(LogLevel::Info), None)
+        .expect_log(
+            Some(LogLevel::Info),
+            Some("#1 policy index: 1 policies (1 rules) shared by 1 hostnames, 1 entries"),
+        )
+        // the estimated size depends on the pointer width of the target
+        .expect_log(Some(LogLevel::Info), None)
+        .execute_and_expect(ReturnType::Bool(true))
+        .unwrap();
+
+    let http_context = 2;
+    module
+        .call_proxy_on_context_create(http_context, root_context)
+        .expect_log(Some(LogLevel::Debug), Some("#2 create_http_context"))
+        .execute_and_expect(ReturnType::None)
+        .unwrap();
+
+    module
+        .call_proxy_on_request_headers(http_context, 0, false)
+        .expect_log(Some(LogLevel::Debug), Some("#2 on_http_request_headers"))
+        .expect_get_header_map_value(Some(MapType::HttpRequestHeaders), Some("traceparent"))
+        .returning(None)
+        .expect_get_header_map_value(Some(MapType::HttpRequestHeaders), Some("tracestate"))
+        .returning(None)
+        .expect_get_header_map_value(Some(MapType::HttpRequestHeaders), Some("baggage"))
+        .returning(None)
+        .expect_get_header_map_value(Some(MapType::HttpRequestHeaders), Some(":authority"))
+        .returning(Some("cars.toystore.com"))
+        .expect_log(
+            Some(LogLevel::Debug),
+            Some("#2 ratelimitpolicy selected some-name (hostname `*.toystore.com`)"),
+        )
+        .expect_grpc_call(
+            Some("limitador-cluster"),
+            Some("envoy.service.ratelimit.v3.RateLimitService"),
+            Some("ShouldRateLimit"),
+            Some(&[0, 0, 0, 0]),
+            Some(&[
+                10, 10, 82, 76, 83, 45, 100, 111, 109, 97, 105, 110, 18, 12, 10, 10, 10, 5, 97,
+                100, 109, 105, 110, 18, 1, 49, 24, 1,
+            ]),
+            Some(5000),
+        )
+        .returning(Some(42))
+        .expect_log(
+            Some(LogLevel::Debug),
+            Some("#2 initiated gRPC call (id# 42) to Limitador"),
+        )
+        .execute_and_expect(ReturnType::Action(Action::Pause))
+        .unwrap();
+}
+
+#[test]
+#[serial]
+fn it_allows_timed_out_calls() {
+    let args = tester::MockSettings {
+        wasm_path: wasm_module(),
+        quiet: false,
+        allow_unexpected: false,
+    };
+    let mut module = tester::mock(args).unwrap();
+    rate_limit_request(&mut module, CONFIG);
+
+    let http_context = 2;
+    module
+        .call_proxy_on_grpc_close(http_context, 42, 4)
+        .expect_log(
+            Some(LogLevel::Debug),
+            Some("#2 on_grpc_call_response: received gRPC call response: token: 42, status: 4"),
+        )
+        .expect_get_buffer_bytes(Some(BufferType::GrpcReceiveBuffer))
+        .returning(None)
+        .expect_log(Some(LogLevel::Warn), Some("grpc response body is empty!"))
+        .execute_and_expect(ReturnType::None)
+        .unwrap();
+
+    module
+        .call_proxy_on_response_headers(http_context, 0, false)
+        .expect_log(Some(LogLevel::Debug), Some("#2 on_http_response_headers"))
+        .execute_and_expect(ReturnType::Action(Action::Continue))
+        .unwrap();
+}
+
+#[test]
+#[serial]
+fn it_denies_other_failures_by_default() {
+    let args = tester::MockSettings {
+        wasm_path: wasm_module(),
+        quiet: false,
+        allow_unexpected: false,
+    };
+    let mut module = tester::mock(args).unwrap();
+    rate_limit_request(&mut module, CONFIG);
+
+    let http_context = 2;
+    let grpc_response: [u8; 1] = [8];
+    module
+        .call_proxy_on_grpc_receive(http_context, 42, grpc_response.len() as i32)
+        .expect_log(
+            Some(LogLevel::Debug),
+            Some("#2 on_grpc_call_response: received gRPC call response: token: 42, status: 0"),
+        )
+        .expect_get_buffer_bytes(Some(BufferType::GrpcReceiveBuffer))
+        .returning(Some(&grpc_response))
+        .expect_log(Some(LogLevel::Warn), None)
+        .expect_log(
+            Some(LogLevel::Debug),
+            Some("#2 denying request on MalformedResponse of the call to limitador-cluster"),
+        )
+        .expect_get_header_map_value(Some(MapType::HttpRequestHeaders), Some("content-type"))
+        .returning(None)
+        .expect_send_local_response(
+            Some(500),
+            Some("Internal Server Error.\n"),
+            Some(vec![]),
+            None,
+        )
+        .execute_and_expect(ReturnType::None)
+        .unwrap();
+}
+
+#[test]
+#[serial]
+fn it_applies_the_failure_mode_of_the_policy_first() {
+    let args = tester::MockSettings {
+        wasm_path: wasm_module(),
+        quiet: false,
+        allow_unexpected: false,
+    };
+    let mut module = tester::mock(args).unwrap();
+    let cfg = r#"{
+        "failureMode": "deny",
+        "rateLimitPolicies": [
+        {
+            "name": "some-name",
+            "domain": "RLS-domain",
+            "service": "limitador-cluster",
+            "hostnames": ["*.toystore.com"],
+            "rules": [
+            {
+                "data": [ { "static": { "key": "admin", "value": "1" } } ]
+            }],
+            "failureMode": { "malformedResponse": "allow" }
+        }]
+    }"#;
+    rate_limit_request(&mut module, cfg);
+
+    let http_context = 2;
+    let grpc_response: [u8; 1] = [8];
+    module
+        .call_proxy_on_grpc_receive(http_context, 42, grpc_response.len() as i32)
+        .expect_log(
+            Some(LogLevel::Debug),
+            Some("#2 on_grpc_call_response: received gRPC call response: token: 42, status: 0"),
+        )
+        .expect_get_buffer_bytes(Some(BufferType::GrpcReceiveBuffer))
+        .returning(Some(&grpc_response))
+        .expect_log(Some(LogLevel::Warn), None)
+        .execute_and_expect(ReturnType::None)
+        .unwrap();
+
+    module
+        .call_proxy_on_response_headers(http_context, 0, false)
+        .expect_log(Some(LogLevel::Debug), Some("#2 on_http_response_headers"))
+        .execute_and_expect(ReturnType::Action(Action::Continue))
+        .unwrap();
+}
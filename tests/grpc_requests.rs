@@ -0,0 +1,223 @@
+use proxy_wasm_test_framework::tester;
+use proxy_wasm_test_framework::types::{Action, BufferType, LogLevel, MapType, ReturnType};
+use serial_test::serial;
+use std::path::Path;
+
+fn wasm_module() -> String {
+    let wasm_file = Path::new("target/wasm32-unknown-unknown/release/wasm_shim.wasm");
+    assert!(
+        wasm_file.exists(),
+        "Run `cargo build --release --target=wasm32-unknown-unknown` first"
+    );
+    wasm_file.to_str().unwrap().to_string()
+}
+
+const CONFIG: &str = r#"{
+    "failureMode": "deny",
+    "rateLimitPolicies": [
+    {
+        "name": "some-name",
+        "domain": "RLS-domain",
+        "service": "limitador-cluster",
+        "hostnames": ["*.toystore.com"],
+        "rules": [
+        {
+            "data": [ { "static": { "key": "admin", "value": "1" } } ]
+        }],
+        "denyResponse": {
+            "contentType": "text/plain",
+            "headers": { "retry-after": "60" }
+        }
+    }]
+}"#;
+
+// Everything up to the dispatch of the `RateLimitRequest` with id 42
+fn rate_limit_request(module: &mut tester::Tester) {
+    module
+        .call_start()
+        .execute_and_expect(ReturnType::None)
+        .unwrap();
+
+    let root_context = 1;
+    module
+        .call_proxy_on_context_create(root_context, 0)
+        .expect_log(Some(LogLevel::Info), Some("#1 set_root_context"))
+        .execute_and_expect(ReturnType::None)
+        .unwrap();
+    module
+        .call_proxy_on_configure(root_context, 0)
+        .expect_log(Some(LogLevel::Info), Some("#1 on_configure"))
+        .expect_get_buffer_bytes(Some(BufferType::PluginConfiguration))
+        .returning(Some(CONFIG.as_bytes()))
+        .expect_log(Some(LogLevel::Info), None)
+        .expect_log(
+            Some(LogLevel::Info),
+            Some("#1 policy index: 1 policies (1 rules) shared by 1 hostnames, 1 entries"),
+        )
+        // the estimated size depends on the pointer width of the target
+        .expect_log(Some(LogLevel::Info), None)
+        .execute_and_expect(ReturnType::Bool(true))
+        .unwrap();
+
+    let http_context = 2;
+    module
+        .call_proxy_on_context_create(http_context, root_context)
+        .expect_log(Some(LogLevel::Debug), Some("#2 create_http_context"))
+        .execute_and_expect(ReturnType::None)
+        .unwrap();
+
+    module
+        .call_proxy_on_request_headers(http_context, 0, false)
+        .expect_log(Some(LogLevel::Debug), Some("#2 on_http_request_headers"))
+        .expect_get_header_map_value(Some(MapType::HttpRequestHeaders), Some("traceparent"))
+        .returning(None)
+        .expect_get_header_map_value(Some(MapType::HttpRequestHeaders), Some("tracestate"))
+        .returning(None)
+        .expect_get_header_map_value(Some(MapType::HttpRequestHeaders), Some("baggage"))
+        .returning(None)
+        .expect_get_header_map_value(Some(MapType::HttpRequestHeaders), Some(":authority"))
+        .returning(Some("cars.toystore.com"))
+        .expect_log(
+            Some(LogLevel::Debug),
+            Some("#2 ratelimitpolicy selected some-name (hostname `*.toystore.com`)"),
+        )
+        .expect_grpc_call(
+            Some("limitador-cluster"),
+            Some("envoy.service.ratelimit.v3.RateLimitService"),
+            Some("ShouldRateLimit"),
+            Some(&[0, 0, 0, 0]),
+            Some(&[
+                10, 10, 82, 76, 83, 45, 100, 111, 109, 97, 105, 110, 18, 12, 10, 10, 10, 5, 97,
+                100, 109, 105, 110, 18, 1, 49, 24, 1,
+            ]),
+            Some(5000),
+        )
+        .returning(Some(42))
+        .expect_log(
+            Some(LogLevel::Debug),
+            Some("#2 initiated gRPC call (id# 42) to Limitador"),
+        )
+        .execute_and_expect(ReturnType::Action(Action::Pause))
+        .unwrap();
+}
+
+#[test]
+#[serial]
+fn it_answers_grpc_clients_when_rate_limited() {
+    let args = tester::MockSettings {
+        wasm_path: wasm_module(),
+        quiet: false,
+        allow_unexpected: false,
+    };
+    let mut module = tester::mock(args).unwrap();
+    rate_limit_request(&mut module);
+
+    let http_context = 2;
+    let grpc_response: [u8; 2] = [8, 2];
+    module
+        .call_proxy_on_grpc_receive(http_context, 42, grpc_response.len() as i32)
+        .expect_log(
+            Some(LogLevel::Debug),
+            Some("#2 on_grpc_call_response: received gRPC call response: token: 42, status: 0"),
+        )
+        .expect_get_buffer_bytes(Some(BufferType::GrpcReceiveBuffer))
+        .returning(Some(&grpc_response))
+        .expect_get_header_map_value(Some(MapType::HttpRequestHeaders), Some("content-type"))
+        .returning(Some("application/grpc+proto"))
+        .expect_send_local_response(
+            Some(200),
+            None,
+            Some(vec![
+                ("retry-after", "60"),
+                ("content-type", "application/grpc"),
+                ("grpc-status", "8"),
+                ("grpc-message", "Too Many Requests"),
+            ]),
+            None,
+        )
+        .execute_and_expect(ReturnType::None)
+        .unwrap();
+}
+
+#[test]
+#[serial]
+fn it_answers_grpc_clients_when_the_service_is_unavailable() {
+    let args = tester::MockSettings {
+        wasm_path: wasm_module(),
+        quiet: false,
+        allow_unexpected: false,
+    };
+    let mut module = tester::mock(args).unwrap();
+    rate_limit_request(&mut module);
+
+    let http_context = 2;
+    module
+        .call_proxy_on_grpc_close(http_context, 42, 14)
+        .expect_log(
+            Some(LogLevel::Debug),
+            Some("#2 on_grpc_call_response: received gRPC call response: token: 42, status: 14"),
+        )
+        .expect_get_buffer_bytes(Some(BufferType::GrpcReceiveBuffer))
+        .returning(None)
+        .expect_log(Some(LogLevel::Warn), Some("grpc response body is empty!"))
+        .expect_log(
+            Some(LogLevel::Debug),
+            Some("#2 denying request on DispatchError of the call to limitador-cluster"),
+        )
+        .expect_get_header_map_value(Some(MapType::HttpRequestHeaders), Some("content-type"))
+        .returning(Some("application/grpc"))
+        .expect_send_local_response(
+            Some(200),
+            None,
+            Some(vec![
+                ("content-type", "application/grpc"),
+                ("grpc-status", "14"),
+                ("grpc-message", "Internal Server Error"),
+            ]),
+            None,
+        )
+        .execute_and_expect(ReturnType::None)
+        .unwrap();
+}
+
+#[test]
+#[serial]
+fn it_answers_grpc_clients_on_an_invalid_response() {
+    let args = tester::MockSettings {
+        wasm_path: wasm_module(),
+        quiet: false,
+        allow_unexpected: false,
+    };
+    let mut module = tester::mock(args).unwrap();
+    rate_limit_request(&mut module);
+
+    let http_context = 2;
+    let grpc_response: [u8; 1] = [8];
+    module
+        .call_proxy_on_grpc_receive(http_context, 42, grpc_response.len() as i32)
+        .expect_log(
+            Some(LogLevel::Debug),
+            Some("#2 on_grpc_call_response: received gRPC call response: token: 42, status: 0"),
+        )
+        .expect_get_buffer_bytes(Some(BufferType::GrpcReceiveBuffer))
+        .returning(Some(&grpc_response))
+        .expect_log(Some(LogLevel::Warn), None)
+        .expect_log(
+            Some(LogLevel::Debug),
+            Some("#2 denying request on MalformedResponse of the call to limitador-cluster"),
+        )
+        .expect_get_header_map_value(Some(MapType::HttpRequestHeaders), Some("content-type"))
+        .returning(Some("application/grpc"))
+        .expect_send_local_response(
+            Some(200),
+            None,
+            Some(vec![
+                ("content-type", "application/grpc"),
+                ("grpc-status", "13"),
+                ("grpc-message", "Internal Server Error"),
+            ]),
+            None,
+        )
+        .execute_and_expect(ReturnType::None)
+        .unwrap();
+}
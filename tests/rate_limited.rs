@@ -44,6 +44,12 @@ fn it_loads() {
         .expect_get_buffer_bytes(Some(BufferType::PluginConfiguration))
         .returning(Some(cfg.as_bytes()))
         .expect_log(Some(LogLevel::Info), None)
+        .expect_log(
+            Some(LogLevel::Info),
+            Some("#1 policy index: 0 policies (0 rules) shared by 0 hostnames, 0 entries"),
+        )
+        // the estimated size depends on the pointer width of the target
+        .expect_log(Some(LogLevel::Info), None)
         .execute_and_expect(ReturnType::Bool(true))
         .unwrap();
 
@@ -148,6 +154,12 @@ fn it_limits() {
         .expect_get_buffer_bytes(Some(BufferType::PluginConfiguration))
         .returning(Some(cfg.as_bytes()))
         .expect_log(Some(LogLevel::Info), None)
+        .expect_log(
+            Some(LogLevel::Info),
+            Some("#1 policy index: 1 policies (1 rules) shared by 2 hostnames, 2 entries"),
+        )
+        // the estimated size depends on the pointer width of the target
+        .expect_log(Some(LogLevel::Info), None)
         .execute_and_expect(ReturnType::Bool(true))
         .unwrap();
 
@@ -177,7 +189,7 @@ fn it_limits() {
         .returning(Some("POST".as_bytes()))
         .expect_log(
             Some(LogLevel::Debug),
-            Some("#2 ratelimitpolicy selected some-name"),
+            Some("#2 ratelimitpolicy selected some-name (hostname `*.toystore.com`)"),
         )
         .expect_grpc_call(
             Some("limitador-cluster"),
@@ -286,6 +298,12 @@ fn it_passes_additional_headers() {
         .expect_get_buffer_bytes(Some(BufferType::PluginConfiguration))
         .returning(Some(cfg.as_bytes()))
         .expect_log(Some(LogLevel::Info), None)
+        .expect_log(
+            Some(LogLevel::Info),
+            Some("#1 policy index: 1 policies (1 rules) shared by 2 hostnames, 2 entries"),
+        )
+        // the estimated size depends on the pointer width of the target
+        .expect_log(Some(LogLevel::Info), None)
         .execute_and_expect(ReturnType::Bool(true))
         .unwrap();
 
@@ -315,7 +333,7 @@ fn it_passes_additional_headers() {
         .returning(Some("POST".as_bytes()))
         .expect_log(
             Some(LogLevel::Debug),
-            Some("#2 ratelimitpolicy selected some-name"),
+            Some("#2 ratelimitpolicy selected some-name (hostname `*.toystore.com`)"),
         )
         .expect_grpc_call(
             Some("limitador-cluster"),
@@ -418,6 +436,12 @@ fn it_rate_limits_with_empty_conditions() {
         .expect_get_buffer_bytes(Some(BufferType::PluginConfiguration))
         .returning(Some(cfg.as_bytes()))
         .expect_log(Some(LogLevel::Info), None)
+        .expect_log(
+            Some(LogLevel::Info),
+            Some("#1 policy index: 1 policies (1 rules) shared by 1 hostnames, 1 entries"),
+        )
+        // the estimated size depends on the pointer width of the target
+        .expect_log(Some(LogLevel::Info), None)
         .execute_and_expect(ReturnType::Bool(true))
         .unwrap();
 
@@ -441,7 +465,7 @@ fn it_rate_limits_with_empty_conditions() {
         .returning(Some("a.com"))
         .expect_log(
             Some(LogLevel::Debug),
-            Some("#2 ratelimitpolicy selected some-name"),
+            Some("#2 ratelimitpolicy selected some-name (hostname `*.com`)"),
         )
         .expect_grpc_call(
             Some("limitador-cluster"),
@@ -529,6 +553,12 @@ fn it_does_not_rate_limits_when_selector_does_not_exist_and_misses_default_value
         .expect_get_buffer_bytes(Some(BufferType::PluginConfiguration))
         .returning(Some(cfg.as_bytes()))
         .expect_log(Some(LogLevel::Info), None)
+        .expect_log(
+            Some(LogLevel::Info),
+            Some("#1 policy index: 1 policies (1 rules) shared by 1 hostnames, 1 entries"),
+        )
+        // the estimated size depends on the pointer width of the target
+        .expect_log(Some(LogLevel::Info), None)
         .execute_and_expect(ReturnType::Bool(true))
         .unwrap();
 
@@ -554,7 +584,7 @@ fn it_does_not_rate_limits_when_selector_does_not_exist_and_misses_default_value
         .returning(None)
         .expect_log(
             Some(LogLevel::Debug),
-            Some("#2 ratelimitpolicy selected some-name"),
+            Some("#2 ratelimitpolicy selected some-name (hostname `*.com`)"),
         )
         .expect_log(
             Some(LogLevel::Debug),
@@ -573,3 +603,363 @@ fn it_does_not_rate_limits_when_selector_does_not_exist_and_misses_default_value
         .execute_and_expect(ReturnType::Action(Action::Continue))
         .unwrap();
 }
+
+#[test]
+#[serial]
+fn it_limits_when_any_domain_is_over_limit() {
+    let args = tester::MockSettings {
+        wasm_path: wasm_module(),
+        quiet: false,
+        allow_unexpected: false,
+    };
+    let mut module = tester::mock(args).unwrap();
+
+    module
+        .call_start()
+        .execute_and_expect(ReturnType::None)
+        .unwrap();
+
+    let root_context = 1;
+    let cfg = r#"{
+        "failureMode": "deny",
+        "rateLimitPolicies": [
+        {
+            "name": "p1",
+            "domain": "toystore-a",
+            "service": "limitador-cluster",
+            "hostnames": ["cars.toystore.com"],
+            "rules": [
+            {
+                "data": [ { "static": { "key": "policy", "value": "p1" } } ]
+            }]
+        },
+        {
+            "name": "p2",
+            "domain": "toystore-a",
+            "service": "limitador-cluster",
+            "hostnames": ["*.toystore.com"],
+            "rules": [
+            {
+                "data": [ { "static": { "key": "policy", "value": "p2" } } ]
+            }]
+        },
+        {
+            "name": "p3",
+            "domain": "toystore-b",
+            "service": "limitador-cluster",
+            "hostnames": ["*.toystore.com"],
+            "rules": [
+            {
+                "data": [ { "static": { "key": "policy", "value": "p3" } } ]
+            }]
+        }]
+    }"#;
+
+    module
+        .call_proxy_on_context_create(root_context, 0)
+        .expect_log(Some(LogLevel::Info), Some("#1 set_root_context"))
+        .execute_and_expect(ReturnType::None)
+        .unwrap();
+    module
+        .call_proxy_on_configure(root_context, 0)
+        .expect_log(Some(LogLevel::Info), Some("#1 on_configure"))
+        .expect_get_buffer_bytes(Some(BufferType::PluginConfiguration))
+        .returning(Some(cfg.as_bytes()))
+        .expect_log(Some(LogLevel::Info), None)
+        .expect_log(
+            Some(LogLevel::Warn),
+            Some("hostname `*.toystore.com` claimed by both policies `p2` and `p3`, merging them"),
+        )
+        .expect_log(
+            Some(LogLevel::Info),
+            Some("#1 policy index: 3 policies (3 rules) shared by 2 hostnames, 3 entries"),
+        )
+        // the estimated size depends on the pointer width of the target
+        .expect_log(Some(LogLevel::Info), None)
+        .execute_and_expect(ReturnType::Bool(true))
+        .unwrap();
+
+    let http_context = 2;
+    module
+        .call_proxy_on_context_create(http_context, root_context)
+        .expect_log(Some(LogLevel::Debug), Some("#2 create_http_context"))
+        .execute_and_expect(ReturnType::None)
+        .unwrap();
+
+    module
+        .call_proxy_on_request_headers(http_context, 0, false)
+        .expect_log(Some(LogLevel::Debug), Some("#2 on_http_request_headers"))
+        .expect_get_header_map_value(Some(MapType::HttpRequestHeaders), Some("traceparent"))
+        .returning(None)
+        .expect_get_header_map_value(Some(MapType::HttpRequestHeaders), Some("tracestate"))
+        .returning(None)
+        .expect_get_header_map_value(Some(MapType::HttpRequestHeaders), Some("baggage"))
+        .returning(None)
+        .expect_get_header_map_value(Some(MapType::HttpRequestHeaders), Some(":authority"))
+        .returning(Some("cars.toystore.com"))
+        .expect_log(
+            Some(LogLevel::Debug),
+            Some("#2 ratelimitpolicy selected p1 (hostname `cars.toystore.com`)"),
+        )
+        .expect_log(
+            Some(LogLevel::Debug),
+            Some("#2 ratelimitpolicy selected p2 (hostname `*.toystore.com`)"),
+        )
+        .expect_log(
+            Some(LogLevel::Debug),
+            Some("#2 ratelimitpolicy selected p3 (hostname `*.toystore.com`)"),
+        )
+        // descriptors of p1 and p2 merged in a single request to their domain
+        .expect_grpc_call(
+            Some("limitador-cluster"),
+            Some("envoy.service.ratelimit.v3.RateLimitService"),
+            Some("ShouldRateLimit"),
+            Some(&[0, 0, 0, 0]),
+            Some(&[
+                10, 10, 116, 111, 121, 115, 116, 111, 114, 101, 45, 97, 18, 14, 10, 12, 10, 6, 112,
+                111, 108, 105, 99, 121, 18, 2, 112, 49, 18, 14, 10, 12, 10, 6, 112, 111, 108, 105,
+                99, 121, 18, 2, 112, 50, 24, 1,
+            ]),
+            Some(5000),
+        )
+        .returning(Some(42))
+        .expect_log(
+            Some(LogLevel::Debug),
+            Some("#2 initiated gRPC call (id# 42) to Limitador"),
+        )
+        .expect_grpc_call(
+            Some("limitador-cluster"),
+            Some("envoy.service.ratelimit.v3.RateLimitService"),
+            Some("ShouldRateLimit"),
+            Some(&[0, 0, 0, 0]),
+            Some(&[
+                10, 10, 116, 111, 121, 115, 116, 111, 114, 101, 45, 98, 18, 14, 10, 12, 10, 6, 112,
+                111, 108, 105, 99, 121, 18, 2, 112, 51, 24, 1,
+            ]),
+            Some(5000),
+        )
+        .returning(Some(43))
+        .expect_log(
+            Some(LogLevel::Debug),
+            Some("#2 initiated gRPC call (id# 43) to Limitador"),
+        )
+        .execute_and_expect(ReturnType::Action(Action::Pause))
+        .unwrap();
+
+    // OK from the first domain, the request waits for the other one
+    let grpc_response: [u8; 2] = [8, 1];
+    module
+        .call_proxy_on_grpc_receive(http_context, 42, grpc_response.len() as i32)
+        .expect_log(
+            Some(LogLevel::Debug),
+            Some("#2 on_grpc_call_response: received gRPC call response: token: 42, status: 0"),
+        )
+        .expect_get_buffer_bytes(Some(BufferType::GrpcReceiveBuffer))
+        .returning(Some(&grpc_response))
+        .execute_and_expect(ReturnType::None)
+        .unwrap();
+
+    // OVER_LIMIT from the second one
+    let grpc_response: [u8; 2] = [8, 2];
+    module
+        .call_proxy_on_grpc_receive(http_context, 43, grpc_response.len() as i32)
+        .expect_log(
+            Some(LogLevel::Debug),
+            Some("#2 on_grpc_call_response: received gRPC call response: token: 43, status: 0"),
+        )
+        .expect_get_buffer_bytes(Some(BufferType::GrpcReceiveBuffer))
+        .returning(Some(&grpc_response))
+        .expect_get_header_map_value(Some(MapType::HttpRequestHeaders), Some("content-type"))
+        .returning(None)
+        .expect_send_local_response(Some(429), Some("Too Many Requests\n"), Some(vec![]), None)
+        .execute_and_expect(ReturnType::None)
+        .unwrap();
+
+    module
+        .call_proxy_on_response_headers(http_context, 0, false)
+        .expect_log(Some(LogLevel::Debug), Some("#2 on_http_response_headers"))
+        .execute_and_expect(ReturnType::Action(Action::Continue))
+        .unwrap();
+}
+
+#[test]
+#[serial]
+fn it_sends_the_deny_response() {
+    let args = tester::MockSettings {
+        wasm_path: wasm_module(),
+        quiet: false,
+        allow_unexpected: false,
+    };
+    let mut module = tester::mock(args).unwrap();
+
+    module
+        .call_start()
+        .execute_and_expect(ReturnType::None)
+        .unwrap();
+
+    let root_context = 1;
+    let cfg = r#"{
+        "failureMode": "deny",
+        "rateLimitPolicies": [
+        {
+            "name": "some-name",
+            "domain": "RLS-domain",
+            "service": "limitador-cluster",
+            "hostnames": ["*.toystore.com"],
+            "rules": [
+            {
+                "data": [ { "static": { "key": "admin", "value": "1" } } ]
+            }],
+            "denyResponse": {
+                "status": 503,
+                "body": "limited admin ${admin}, retry later",
+                "contentType": "text/plain",
+                "headers": { "retry-after": "60" }
+            }
+        }]
+    }"#;
+
+    module
+        .call_proxy_on_context_create(root_context, 0)
+        .expect_log(Some(LogLevel::Info), Some("#1 set_root_context"))
+        .execute_and_expect(ReturnType::None)
+        .unwrap();
+    module
+        .call_proxy_on_configure(root_context, 0)
+        .expect_log(Some(LogLevel::Info), Some("#1 on_configure"))
+        .expect_get_buffer_bytes(Some(BufferType::PluginConfiguration))
+        .returning(Some(cfg.as_bytes()))
+        .expect_log(Some(LogLevel::Info), None)
+        .expect_log(
+            Some(LogLevel::Info),
+            Some("#1 policy index: 1 policies (1 rules) shared by 1 hostnames, 1 entries"),
+        )
+        // the estimated size depends on the pointer width of the target
+        .expect_log(Some(LogLevel::Info), None)
+        .execute_and_expect(ReturnType::Bool(true))
+        .unwrap();
+
+    let http_context = 2;
+    module
+        .call_proxy_on_context_create(http_context, root_context)
+        .expect_log(Some(LogLevel::Debug), Some("#2 create_http_context"))
+        .execute_and_expect(ReturnType::None)
+        .unwrap();
+
+    module
+        .call_proxy_on_request_headers(http_context, 0, false)
+        .expect_log(Some(LogLevel::Debug), Some("#2 on_http_request_headers"))
+        .expect_get_header_map_value(Some(MapType::HttpRequestHeaders), Some("traceparent"))
+        .returning(None)
+        .expect_get_header_map_value(Some(MapType::HttpRequestHeaders), Some("tracestate"))
+        .returning(None)
+        .expect_get_header_map_value(Some(MapType::HttpRequestHeaders), Some("baggage"))
+        .returning(None)
+        .expect_get_header_map_value(Some(MapType::HttpRequestHeaders), Some(":authority"))
+        .returning(Some("cars.toystore.com"))
+        .expect_log(
+            Some(LogLevel::Debug),
+            Some("#2 ratelimitpolicy selected some-name (hostname `*.toystore.com`)"),
+        )
+        .expect_grpc_call(
+            Some("limitador-cluster"),
+            Some("envoy.service.ratelimit.v3.RateLimitService"),
+            Some("ShouldRateLimit"),
+            Some(&[0, 0, 0, 0]),
+            Some(&[
+                10, 10, 82, 76, 83, 45, 100, 111, 109, 97, 105, 110, 18, 12, 10, 10, 10, 5, 97,
+                100, 109, 105, 110, 18, 1, 49, 24, 1,
+            ]),
+            Some(5000),
+        )
+        .returning(Some(42))
+        .expect_log(
+            Some(LogLevel::Debug),
+            Some("#2 initiated gRPC call (id# 42) to Limitador"),
+        )
+        .execute_and_expect(ReturnType::Action(Action::Pause))
+        .unwrap();
+
+    let grpc_response: [u8; 2] = [8, 2];
+    module
+        .call_proxy_on_grpc_receive(http_context, 42, grpc_response.len() as i32)
+        .expect_log(
+            Some(LogLevel::Debug),
+            Some("#2 on_grpc_call_response: received gRPC call response: token: 42, status: 0"),
+        )
+        .expect_get_buffer_bytes(Some(BufferType::GrpcReceiveBuffer))
+        .returning(Some(&grpc_response))
+        .expect_get_header_map_value(Some(MapType::HttpRequestHeaders), Some("content-type"))
+        .returning(None)
+        .expect_send_local_response(
+            Some(503),
+            Some("limited admin 1, retry later"),
+            Some(vec![("content-type", "text/plain"), ("retry-after", "60")]),
+            None,
+        )
+        .execute_and_expect(ReturnType::None)
+        .unwrap();
+
+    module
+        .call_proxy_on_response_headers(http_context, 0, false)
+        .expect_log(Some(LogLevel::Debug), Some("#2 on_http_response_headers"))
+        .execute_and_expect(ReturnType::Action(Action::Continue))
+        .unwrap();
+}
+
+#[test]
+#[serial]
+fn it_warns_of_policies_merged_on_a_hostname() {
+    let args = tester::MockSettings {
+        wasm_path: wasm_module(),
+        quiet: false,
+        allow_unexpected: false,
+    };
+    let mut module = tester::mock(args).unwrap();
+
+    module
+        .call_start()
+        .execute_and_expect(ReturnType::None)
+        .unwrap();
+
+    let root_context = 1;
+    let cfg = r#"{
+        "failureMode": "deny",
+        "rateLimitPolicies": [
+        {
+            "name": "rlp-a",
+            "domain": "toystore-a",
+            "service": "limitador-cluster",
+            "hostnames": ["*.toystore.com"],
+            "rules": []
+        },
+        {
+            "name": "rlp-b",
+            "domain": "toystore-b",
+            "service": "limitador-cluster",
+            "hostnames": ["*.toystore.com"],
+            "rules": []
+        }]
+    }"#;
+
+    module
+        .call_proxy_on_context_create(root_context, 0)
+        .expect_log(Some(LogLevel::Info), Some("#1 set_root_context"))
+        .execute_and_expect(ReturnType::None)
+        .unwrap();
+    module
+        .call_proxy_on_configure(root_context, 0)
+        .expect_log(Some(LogLevel::Info), Some("#1 on_configure"))
+        .expect_get_buffer_bytes(Some(BufferType::PluginConfiguration))
+        .returning(Some(cfg.as_bytes()))
+        .expect_log(Some(LogLevel::Info), None)
+        .expect_log(
+            Some(LogLevel::Warn),
+            Some("hostname `*.toystore.com` claimed by both policies `rlp-a` and `rlp-b`, merging them"),
+        )
+        .expect_log(Some(LogLevel::Info), Some("#1 policy index: 2 policies (0 rules) shared by 1 hostnames, 2 entries"))
+        // the estimated size depends on the pointer width of the target
+        .expect_log(Some(LogLevel::Info), None)
+        .execute_and_expect(ReturnType::Bool(true))
+        .unwrap();
+}